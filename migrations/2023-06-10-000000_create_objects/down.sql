@@ -0,0 +1,2 @@
+DROP TABLE variants;
+DROP TABLE objects;
@@ -0,0 +1,31 @@
+CREATE TABLE objects
+(
+	bucket     TEXT    NOT NULL,
+	hash       TEXT    NOT NULL,
+	size       BIGINT  NOT NULL,
+	mime       TEXT    NOT NULL,
+	filename   TEXT,
+	created_at BIGINT  NOT NULL,
+	uploader   TEXT,
+
+	PRIMARY KEY (bucket, hash)
+);
+
+CREATE INDEX objects_created_at ON objects (bucket, created_at);
+
+-- 对象的变体也按 Hash 保存为普通的对象，这里只记录它属于哪个对象，以及使用的编码。
+-- 图片的格式变体没有编码，按类型选择，类型和大小从变体自己的记录读取。
+CREATE TABLE variants
+(
+	bucket TEXT NOT NULL,
+	object TEXT NOT NULL,
+	hash   TEXT NOT NULL,
+	codec  TEXT,
+
+	PRIMARY KEY (bucket, object, hash),
+	FOREIGN KEY (bucket, object) REFERENCES objects (bucket, hash) ON DELETE CASCADE,
+	FOREIGN KEY (bucket, hash) REFERENCES objects (bucket, hash) ON DELETE CASCADE
+);
+
+-- 检查对象是否是变体（列表中排除变体、删除时检查是否被共用）时按 Hash 查找。
+CREATE INDEX variants_hash ON variants (bucket, hash);
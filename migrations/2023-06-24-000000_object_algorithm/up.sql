@@ -0,0 +1,2 @@
+-- 之前的版本只支持 XXH3。
+ALTER TABLE objects ADD COLUMN algorithm TEXT NOT NULL DEFAULT 'xxh3';
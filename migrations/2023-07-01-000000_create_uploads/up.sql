@@ -0,0 +1,11 @@
+-- 断点续传（tus）中未完成的上传，已接收的部分保存在 buf_dir/tus 目录里，大小就是偏移。
+CREATE TABLE uploads
+(
+	id         TEXT    NOT NULL PRIMARY KEY,
+	bucket     TEXT    NOT NULL,
+	length     BIGINT  NOT NULL,
+	metadata   TEXT,
+	expires_at BIGINT  NOT NULL
+);
+
+CREATE INDEX uploads_expires_at ON uploads (expires_at);
@@ -0,0 +1,2 @@
+DROP TABLE s3_uploads;
+DROP TABLE s3_keys;
@@ -0,0 +1,25 @@
+-- S3 接口的对象以 Key 访问，这里记录 Key 到 Hash 的映射，同一个文件可以有多个 Key。
+CREATE TABLE s3_keys
+(
+	bucket     TEXT    NOT NULL,
+	key        TEXT    NOT NULL,
+	hash       TEXT    NOT NULL,
+	created_at BIGINT  NOT NULL,
+
+	PRIMARY KEY (bucket, key),
+	FOREIGN KEY (bucket, hash) REFERENCES objects (bucket, hash) ON DELETE CASCADE
+);
+
+CREATE INDEX s3_keys_hash ON s3_keys (bucket, hash);
+
+-- 未完成的分段上传，各段保存在 buf_dir/s3/<id> 目录里。
+CREATE TABLE s3_uploads
+(
+	id         TEXT    NOT NULL PRIMARY KEY,
+	bucket     TEXT    NOT NULL,
+	key        TEXT    NOT NULL,
+
+	-- 创建时声明的 Content-Type，完成时用于检查类型。
+	mime       TEXT,
+	created_at BIGINT  NOT NULL
+);
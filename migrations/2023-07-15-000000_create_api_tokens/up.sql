@@ -0,0 +1,15 @@
+-- API 令牌，只保存令牌的 SHA-256，明文只在创建时返回一次。
+CREATE TABLE api_tokens
+(
+	id         TEXT    NOT NULL PRIMARY KEY,
+	token_hash TEXT    NOT NULL UNIQUE,
+	name       TEXT    NOT NULL,
+
+	-- 逗号分隔的权限，见 token.rs 的 Scope。
+	scopes     TEXT    NOT NULL,
+
+	-- 限定可访问的存储桶，为 NULL 则不限。
+	bucket     TEXT,
+	expires_at BIGINT,
+	created_at BIGINT  NOT NULL
+);
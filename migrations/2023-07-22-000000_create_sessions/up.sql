@@ -0,0 +1,12 @@
+-- 登录的会话，Cookie 里是随机的会话令牌，这里只保存它的 SHA-256。
+-- id 是另外生成的，用于列出和注销会话，不能用来登录。
+CREATE TABLE sessions
+(
+	id         TEXT    NOT NULL PRIMARY KEY,
+	token_hash TEXT    NOT NULL UNIQUE,
+	user_agent TEXT,
+	created_at BIGINT  NOT NULL,
+	expires_at BIGINT  NOT NULL
+);
+
+CREATE INDEX sessions_expires_at ON sessions (expires_at);
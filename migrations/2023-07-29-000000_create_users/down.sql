@@ -0,0 +1,14 @@
+DROP TABLE sessions;
+
+CREATE TABLE sessions
+(
+	id         TEXT    NOT NULL PRIMARY KEY,
+	token_hash TEXT    NOT NULL UNIQUE,
+	user_agent TEXT,
+	created_at BIGINT  NOT NULL,
+	expires_at BIGINT  NOT NULL
+);
+
+CREATE INDEX sessions_expires_at ON sessions (expires_at);
+
+DROP TABLE users;
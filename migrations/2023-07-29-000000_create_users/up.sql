@@ -0,0 +1,23 @@
+-- 用户账户，密码保存为 Argon2id 的 PHC 字符串，角色见 user.rs 的 Role。
+CREATE TABLE users
+(
+	name          TEXT    NOT NULL PRIMARY KEY,
+	password_hash TEXT    NOT NULL,
+	role          TEXT    NOT NULL,
+	created_at    BIGINT  NOT NULL
+);
+
+-- 会话改为属于某个用户，旧的会话没有用户，直接丢弃让大家重新登录。
+DROP TABLE sessions;
+
+CREATE TABLE sessions
+(
+	id         TEXT    NOT NULL PRIMARY KEY,
+	token_hash TEXT    NOT NULL UNIQUE,
+	username   TEXT    NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+	user_agent TEXT,
+	created_at BIGINT  NOT NULL,
+	expires_at BIGINT  NOT NULL
+);
+
+CREATE INDEX sessions_expires_at ON sessions (expires_at);
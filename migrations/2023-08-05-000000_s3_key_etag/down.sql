@@ -0,0 +1 @@
+ALTER TABLE s3_keys DROP COLUMN etag;
@@ -0,0 +1,3 @@
+-- S3 客户端用 ETag 检查内容，需要与 S3 相同：普通上传是 MD5，分段上传是各段 MD5 的 MD5 加上段数。
+-- 之前的版本用 Hash 作为 ETag，这些 Key 没有记录，仍然使用 Hash。
+ALTER TABLE s3_keys ADD COLUMN etag TEXT;
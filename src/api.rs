@@ -1,26 +1,184 @@
 use axum::{http::StatusCode, response::IntoResponse};
-use axum::extract::State;
+use std::net::SocketAddr;
+use std::time::{Instant, SystemTime};
+
+use axum::extract::{ConnectInfo, OriginalUri, State};
+use axum::http::header::{AUTHORIZATION, RETRY_AFTER, USER_AGENT};
+use axum::http::{HeaderMap, Request};
+use axum::Json;
+use axum::middleware::Next;
 use axum::response::Response;
-use axum::routing::post;
-use axum_extra::extract::cookie::Cookie;
 use axum_extra::extract::CookieJar;
-use cookie::time::{Duration, OffsetDateTime};
+use serde::Deserialize;
+use tokio::task::spawn_blocking;
 
 use crate::context::OSSContext;
+use crate::db::unix_time;
+use crate::session::{check_session, create_session, remove_session};
+use crate::token::{check_token, Scope, TokenError};
+use crate::presign::PresignError;
+use crate::user::{authenticate, count_users};
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+	username: String,
+	password: String,
+}
+
+/// 用账户登录，成功后创建会话。没有任何用户且允许匿名访问时不需要登录，直接返回成功。
+///
+/// 失败次数过多时返回 429，见 limiter.rs。
+pub async fn login(
+	State(ctx): State<OSSContext>,
+	ConnectInfo(peer): ConnectInfo<SocketAddr>,
+	jar: CookieJar,
+	headers: HeaderMap,
+	Json(body): Json<LoginRequest>,
+) -> Response {
+	if ctx.allow_anonymous && count_users(&ctx.db).is_ok_and(|n| n == 0) {
+		return StatusCode::NO_CONTENT.into_response();
+	}
+
+	let ip = ctx.limiter.client_ip(peer.ip(), &headers);
+	let attempt = match ctx.limiter.check(ip, Instant::now()) {
+		Ok(attempt) => attempt,
+		Err(wait) => {
+			let retry_after = wait.as_secs() + (wait.subsec_nanos() > 0) as u64;
+			return (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, retry_after.to_string())]).into_response();
+		}
+	};
+
+	let permit = ctx.limiter.hashing_permit().await;
+	let db = ctx.db.clone();
+	let task = spawn_blocking(move || authenticate(&db, &body.username, &body.password));
+	let result = task.await.unwrap();
+	drop(permit);
+
+	let user = match result {
+		Ok(Some(user)) => user,
+		Ok(None) => {
+			log::info!("Failed login from {}", ip);
+			attempt.failure(Instant::now());
+			return StatusCode::BAD_REQUEST.into_response();
+		}
+		Err(e) => {
+			log::error!("Failed to authenticate: {}", e);
+			return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+		}
+	};
+	attempt.success();
+
+	let user_agent = headers.get(USER_AGENT).and_then(|v| v.to_str().ok());
+	return match create_session(&ctx, jar, &user.name, user_agent) {
+		Ok(jar) => {
+			log::info!("User {} logged in from {}", user.name, ip);
+			(StatusCode::NO_CONTENT, jar).into_response()
+		}
+		Err(e) => {
+			log::error!("Failed to create session: {}", e);
+			StatusCode::INTERNAL_SERVER_ERROR.into_response()
+		}
+	};
+}
 
-pub async fn login(State(ctx): State<OSSContext>, jar: CookieJar, body: String) -> Response {
-	let password = match ctx.password {
-		Some(value) => value,
-		None => return StatusCode::NO_CONTENT.into_response(),
+/// 注销当前的会话，不需要登录，因为它只影响请求自己带的 Cookie。
+pub async fn logout(State(ctx): State<OSSContext>, jar: CookieJar) -> Response {
+	return match remove_session(&ctx.db, jar) {
+		Ok(jar) => (StatusCode::NO_CONTENT, jar).into_response(),
+		Err(e) => {
+			log::error!("Failed to remove session: {}", e);
+			StatusCode::INTERNAL_SERVER_ERROR.into_response()
+		}
 	};
+}
+
+/// 一组路由的访问要求，作为 `authorize` 中间件的状态。
+#[derive(Clone)]
+pub struct Guard {
+	pub ctx: OSSContext,
+	pub scope: Scope,
 
-	if body != password {
-		return StatusCode::BAD_REQUEST.into_response();
+	/// 路由所属的存储桶，为 None 表示全局的管理接口。
+	pub bucket: Option<String>,
+
+	/// 是否接受预签名的 URL，见 presign.rs。
+	pub presign: bool,
+}
+
+/// 发出请求的用户或令牌，由 `authorize` 放入请求的扩展里，用于记录上传者等。
+/// 预签名的请求没有身份。
+#[derive(Clone, Debug)]
+pub struct Identity(pub String);
+
+/// 依次检查登录的会话、API 令牌和预签名，任意一个通过即可。
+///
+/// 带了令牌却无效时返回 401，让客户端知道需要换一个令牌，而权限不足则是 403。
+/// 什么凭据都没带的请求见 `anonymous`。
+pub async fn authorize<B>(
+	State(guard): State<Guard>,
+	jar: CookieJar,
+	OriginalUri(uri): OriginalUri,
+	mut request: Request<B>,
+	next: Next<B>,
+) -> Response {
+	match check_session(&guard.ctx.db, &jar) {
+		Ok(Some(user)) if user.role.allows(guard.scope) => {
+			request.extensions_mut().insert(Identity(user.name));
+			return next.run(request).await;
+		}
+		Ok(Some(_)) => return StatusCode::FORBIDDEN.into_response(),
+		Ok(None) => {}
+		Err(e) => {
+			log::error!("Failed to check session: {}", e);
+			return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+		}
 	}
 
-	let mut cookie = Cookie::new("password", password);
-	cookie.set_http_only(true);
-	cookie.set_expires(OffsetDateTime::now_utc() + Duration::weeks(52));
+	let bearer = request.headers().get(AUTHORIZATION)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.strip_prefix("Bearer "));
 
-	return (StatusCode::NO_CONTENT, jar.add(cookie)).into_response();
+	if let Some(token) = bearer {
+		return match check_token(&guard.ctx.db, token.trim(), guard.scope, guard.bucket.as_deref()) {
+			Ok(Ok(name)) => {
+				request.extensions_mut().insert(Identity(format!("token:{}", name)));
+				next.run(request).await
+			}
+			Ok(Err(TokenError::Invalid)) => StatusCode::UNAUTHORIZED.into_response(),
+			Ok(Err(TokenError::Forbidden)) => StatusCode::FORBIDDEN.into_response(),
+			Err(e) => {
+				log::error!("Failed to check API token: {}", e);
+				StatusCode::INTERNAL_SERVER_ERROR.into_response()
+			}
+		};
+	}
+
+	if !guard.presign {
+		return anonymous(&guard.ctx, request, next).await;
+	}
+	let now = unix_time(SystemTime::now()) as u64;
+	return match guard.ctx.presigner.verify(request.method(), &uri, request.headers(), now) {
+		Ok(_) => next.run(request).await,
+		Err(PresignError::Missing) => anonymous(&guard.ctx, request, next).await,
+		Err(e) => {
+			log::debug!("Presigned request to {} rejected: {:?}", uri.path(), e);
+			e.into_response()
+		}
+	};
+}
+
+/// 没有凭据的请求需要登录，返回 401。
+///
+/// 每次都查询用户数量，启动后才创建的账户立即生效。没有任何账户时无法登录，
+/// 配置了 allow_anonymous 则放行，否则返回 503，而不是悄悄地公开所有接口。
+async fn anonymous<B>(ctx: &OSSContext, request: Request<B>, next: Next<B>) -> Response {
+	return match count_users(&ctx.db) {
+		Ok(0) if ctx.allow_anonymous => next.run(request).await,
+		Ok(0) => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+		Ok(_) => StatusCode::UNAUTHORIZED.into_response(),
+		Err(e) => {
+			log::error!("Failed to count users: {}", e);
+			StatusCode::INTERNAL_SERVER_ERROR.into_response()
+		}
+	};
 }
@@ -0,0 +1,83 @@
+use std::io;
+
+use async_compression::Level;
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use axum::body::Bytes;
+use axum::http::HeaderMap;
+use futures::Stream;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use serde::Deserialize;
+use tokio::fs::File;
+use tokio::io::BufReader;
+use tokio_util::io::ReaderStream;
+
+use crate::negotiate::{match_mime, preferred_encodings};
+
+/// 动态压缩支持的编码，顺序即为权重相同时的偏好。
+const ENCODINGS: [&str; 3] = ["br", "zstd", "gzip"];
+
+/// 动态压缩的配置，对应配置文件中的 `[compression]` 部分。
+///
+/// 动态压缩会消耗 CPU，且响应没有 Content-Length，所以只用于文本类的小文件，
+/// 大文件和静态资源最好还是预先压缩好。
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct CompressOptions {
+	/// 是否启用，默认为 true。
+	pub enabled: bool,
+
+	/// 可压缩的 MIME 类型，支持 `text/*` 这样的通配。
+	pub mime_types: Vec<String>,
+
+	/// 小于该大小（字节）的文件不压缩，因为省下的流量还抵不上头部和压缩的开销。
+	pub min_size: u64,
+}
+
+impl Default for CompressOptions {
+	fn default() -> Self {
+		CompressOptions {
+			enabled: true,
+			mime_types: vec![
+				"text/*".into(),
+				"application/javascript".into(),
+				"application/json".into(),
+				"application/xml".into(),
+				"image/svg+xml".into(),
+			],
+			min_size: 1024,
+		}
+	}
+}
+
+impl CompressOptions {
+
+	/// 判断该类型和大小的文件是否需要动态压缩，与请求无关，可用于决定 Vary 头。
+	pub fn is_compressible(&self, mime: &str, size: u64) -> bool {
+		if !self.enabled || size < self.min_size {
+			return false;
+		}
+		return self.mime_types.iter().any(|pattern| match_mime(pattern, mime));
+	}
+
+	/// 根据 Accept-Encoding 选择压缩编码，返回 None 表示发送原始内容。
+	pub fn negotiate(&self, headers: &HeaderMap) -> Option<&'static str> {
+		preferred_encodings(headers, &ENCODINGS).first().copied()
+	}
+}
+
+/// 边读边压缩，不会把整个文件载入内存。
+///
+/// 动态压缩对速度要求高，所以 brotli 没有用默认的最高等级（11），
+/// 那个等级压缩文本要比 gzip 慢上百倍，只适合预压缩。
+pub fn encode(file: File, encoding: &str) -> impl Stream<Item=io::Result<Bytes>> {
+	let reader = BufReader::new(file);
+
+	let stream: BoxStream<io::Result<Bytes>> = match encoding {
+		"br" => ReaderStream::new(BrotliEncoder::with_quality(reader, Level::Precise(4))).boxed(),
+		"zstd" => ReaderStream::new(ZstdEncoder::new(reader)).boxed(),
+		"gzip" => ReaderStream::new(GzipEncoder::new(reader)).boxed(),
+		_ => unreachable!("Unsupported encoding: {}", encoding),
+	};
+	return stream;
+}
@@ -1,17 +1,38 @@
+use std::error::Error;
+use std::fmt;
 use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
 
 use axum::extract::BodyStream;
-use base64::{Engine as _, engine::general_purpose};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
 use futures::StreamExt;
+use md5::{Digest, Md5};
+use diesel::prelude::*;
 use serde::Serialize;
-use tempfile::{NamedTempFile, PersistError};
-use xxhash_rust::xxh3::Xxh3;
+use tempfile::{NamedTempFile, TempPath};
+use tokio_util::io::ReaderStream;
+
+use crate::compress::CompressOptions;
+use crate::digest::{DigestVerifier, ExpectedDigest};
+use crate::db::{DbPool, DbResult, Object, unix_time};
+use crate::hash::{HashAlgorithm, HashOptions, Hasher};
+use crate::limiter::LoginLimiter;
+use crate::negotiate::match_mime;
+use crate::presign::Presigner;
+use crate::session::SessionOptions;
+use crate::tus::UploadLocks;
+use crate::schema::objects;
 
 #[derive(Serialize)]
 pub struct UploadVO {
 	pub hash: String,
+
+	/// 相同内容的对象已经存在，返回的是原有的记录。
+	pub existed: bool,
 }
 
 /// 业务逻辑的状态全，刚玩 Rust 所以弄得简单点，都保存在这一个对象里。
@@ -19,26 +40,179 @@ pub struct UploadVO {
 pub struct OSSContext {
 	pub data_dir: PathBuf,
 	pub buf_dir: PathBuf,
-	pub password: Option<String>,
+	/// 没有任何用户账户时是否允许匿名访问需要登录的接口，见 `api::anonymous`。
+	pub allow_anonymous: bool,
+	pub presigner: Presigner,
+	pub session: Arc<SessionOptions>,
+	pub limiter: LoginLimiter,
+	pub compress: Arc<CompressOptions>,
+	pub db: DbPool,
+	/// 所有存储桶共用，过期清理也要先取得锁，以免删掉正在追加的上传。
+	pub upload_locks: UploadLocks,
+}
+
+/// 保存对象时需要记录的信息，Hash、大小和类型由 FileBuf 自己计算。
+pub struct UploadMeta {
+	pub bucket: String,
+	pub filename: Option<String>,
+	pub uploader: Option<String>,
+}
+
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// 浏览器会执行其中脚本的类型（此外还有所有的 `text/*`），从存储桶的域名返回它们，
+/// 就等于允许上传者在这个域名下运行代码，所以必须在存储桶的允许列表中明确写出。
+const ACTIVE_TYPES: [&str; 4] = ["image/svg+xml", "application/xhtml+xml", "application/xml", "application/javascript"];
+
+fn is_active_type(mime: &str) -> bool {
+	return mime.starts_with("text/") || ACTIVE_TYPES.contains(&mime);
+}
+
+/// 检查类型是否在允许列表中，`image/*` 这样的通配和空列表都不包括 ACTIVE_TYPES 和 `text/*`。
+pub fn is_allowed_type(allowed: &[String], mime: &str) -> bool {
+	if is_active_type(mime) {
+		return allowed.iter().any(|p| p.eq_ignore_ascii_case(mime));
+	}
+	return allowed.is_empty() || allowed.iter().any(|p| match_mime(p, mime));
+}
+
+/// 检查文件类型时读取的文件头长度，足够识别常见的格式。
+const SNIFF_SIZE: usize = 8192;
+
+/// 接收上传时的检查项，检查失败会立即停止接收并删除临时文件。
+pub struct ReceiveOptions<'a> {
+	/// 客户端声明的类型，即请求的 Content-Type。
+	pub claimed_type: Option<&'a str>,
+
+	/// 允许的类型，支持 `image/*` 这样的通配，为空则不限制，脚本类型除外（见 is_allowed_type）。
+	pub allowed_types: &'a [String],
+
+	/// 计算 Hash 的算法和格式。
+	pub hash: &'a HashOptions,
+
+	/// 客户端附带的摘要，接收完后检查，不匹配则不保存。
+	pub digests: Vec<ExpectedDigest>,
+
+	/// 最大的字节数，超过时立即停止接收，已写入的临时文件随之删除。
+	pub max_size: Option<u64>,
+
+	/// 同时计算内容的 MD5，S3 接口用它作为 ETag。
+	pub md5: bool,
+}
+
+impl ReceiveOptions<'_> {
+
+	/// 确定文件的真实类型并检查是否允许，能从文件头识别出的以识别结果为准。
+	///
+	/// 文本格式（SVG、JSON 等）无法识别，只能相信客户端声明的类型，
+	/// 但如果声明的是能识别的二进制格式却没识别出来，说明内容与类型不符。
+	///
+	/// 没有明确允许的脚本类型（见 is_allowed_type）降级为 octet-stream，
+	/// 如果存储桶允许 octet-stream 就照常保存，下载时浏览器不会执行它。
+	fn check_type(&self, head: &[u8]) -> Result<String, ReceiveError> {
+		let claimed = self.claimed_type
+			.map(|v| v.split(';').next().unwrap().trim().to_ascii_lowercase())
+			.filter(|v| !v.is_empty() && v != OCTET_STREAM);
+
+		let mime = match infer::get(head) {
+			Some(kind) => kind.mime_type().to_owned(),
+			None => match claimed {
+				Some(claimed) if infer::is_mime_supported(&claimed) => {
+					return Err(ReceiveError::Type(claimed));
+				}
+				Some(claimed) => claimed,
+				None => OCTET_STREAM.to_owned(),
+			},
+		};
+
+		if is_allowed_type(self.allowed_types, &mime) {
+			return Ok(mime);
+		}
+		if is_active_type(&mime) && is_allowed_type(self.allowed_types, OCTET_STREAM) {
+			return Ok(OCTET_STREAM.to_owned());
+		}
+		return Err(ReceiveError::Type(mime));
+	}
+}
+
+#[derive(Debug)]
+pub enum ReceiveError {
+	/// 读取请求体出错，通常是客户端断开了连接。
+	Body(axum::Error),
+
+	/// 文件的类型不允许上传，或者与声明的类型不符。
+	Type(String),
+
+	/// 摘要头格式错误，或者与收到的内容不符。
+	Digest(String),
+
+	/// 超过了存储桶的大小限制。
+	TooLarge,
+
+	/// 读写临时文件出错。
+	Io(io::Error),
+}
+
+impl IntoResponse for ReceiveError {
+	fn into_response(self) -> Response {
+		match self {
+			ReceiveError::Body(e) => {
+				log::debug!("Failed to receive upload: {}", e);
+				StatusCode::BAD_REQUEST.into_response()
+			}
+			ReceiveError::Type(mime) => {
+				log::warn!("Rejected upload of type {}", mime);
+				StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response()
+			}
+			ReceiveError::Digest(message) => {
+				log::warn!("Rejected upload: {}", message);
+				(StatusCode::BAD_REQUEST, message).into_response()
+			}
+			ReceiveError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+			ReceiveError::Io(e) => {
+				log::error!("Failed to read upload: {}", e);
+				StatusCode::INTERNAL_SERVER_ERROR.into_response()
+			}
+		}
+	}
 }
 
 impl OSSContext {
 
 	/// 接收上传的对象到临时文件，并计算 Hash，稍后可以决定是否保存。
 	/// 这样能避免过大的文件消耗内存，适用于不需要在程序内处理的情况。
-	pub async fn receive_file(&self, body: BodyStream) -> Result<FileBuf, axum::Error> {
-		return FileBuf::receive(self, body).await;
+	pub async fn receive_file(
+		&self,
+		body: BodyStream,
+		options: ReceiveOptions<'_>,
+	) -> Result<FileBuf, ReceiveError> {
+		return FileBuf::receive(self, body, options).await;
+	}
+
+	/// 与 `receive_file` 相同，但内容来自已有的文件，文件必须位于 buf_dir 中。
+	pub async fn inspect_file(
+		&self,
+		path: PathBuf,
+		options: ReceiveOptions<'_>,
+	) -> Result<FileBuf, ReceiveError> {
+		return FileBuf::inspect(self, path, options).await;
 	}
 }
 
 pub struct FileBuf {
 	target: PathBuf,
+	db: DbPool,
+
+	pub size: u64,
+
+	/// 文件的真实类型，见 `ReceiveOptions::check_type`。
+	pub mime: String,
 
 	pub file: NamedTempFile,
 
-	/// 20 个字符的 URL-Safe base64 字符串，是文件的 Hash。
+	/// 文件的 Hash，默认是 20 个字符的 URL-Safe base64 字符串，可以在存储桶中配置。
 	///
-	/// 之所以选择 20 个字符，是因为它有 120 bit，接近原始输出 128，
+	/// 默认之所以选择 20 个字符，是因为它有 120 bit，接近原始输出 128，
 	/// 且能被 6(base64) 和 8(byte) 整除。
 	///
 	/// 【大小写与文件系统】
@@ -55,9 +229,83 @@ pub struct FileBuf {
 	/// 由生日问题可以得出，104 bit 需要一千四百亿输入才能达到一亿分之一的碰撞率。
 	/// https://en.wikipedia.org/wiki/Birthday_attack
 	///
-	/// 【为什么不用 Hex】
+	/// 【为什么默认不用 Hex】
 	/// 我有强迫症，能省几个字符坚决不用更长的，而且文件名太长也不好看。
 	pub hash: String,
+
+	/// 计算 Hash 的算法，会记录到索引中，这样修改配置后旧对象的记录仍然有意义。
+	pub algorithm: HashAlgorithm,
+
+	/// 内容的 MD5，只在 `ReceiveOptions::md5` 为 true 时计算。
+	pub md5: Option<Vec<u8>>,
+}
+
+/// 在接收文件的同时计算 Hash、检查类型和摘要。
+struct Inspector<'a> {
+	options: ReceiveOptions<'a>,
+	hasher: Hasher,
+	verifier: DigestVerifier,
+	md5: Option<Md5>,
+	size: u64,
+
+	/// 文件头收集够了就检查类型，不允许的文件无需等到上传完。
+	head: Vec<u8>,
+	mime: Option<String>,
+}
+
+impl<'a> Inspector<'a> {
+
+	fn new(mut options: ReceiveOptions<'a>) -> Self {
+		// 默认的 XXH3 是非加密 Hash，速度快，但有恶意碰撞的风险，在允许公开上传时需要注意。
+		let hasher = options.hash.hasher();
+		let verifier = DigestVerifier::new(std::mem::take(&mut options.digests));
+		let md5 = options.md5.then(Md5::new);
+		let head = Vec::with_capacity(SNIFF_SIZE);
+		return Inspector { options, hasher, verifier, md5, size: 0, head, mime: None };
+	}
+
+	fn update(&mut self, data: &[u8]) -> Result<(), ReceiveError> {
+		self.size += data.len() as u64;
+		if self.options.max_size.is_some_and(|max| self.size > max) {
+			return Err(ReceiveError::TooLarge);
+		}
+		self.hasher.update(data);
+		self.verifier.update(data);
+		if let Some(md5) = &mut self.md5 {
+			md5.update(data);
+		}
+
+		if self.mime.is_none() {
+			let n = (SNIFF_SIZE - self.head.len()).min(data.len());
+			self.head.extend_from_slice(&data[..n]);
+			if self.head.len() == SNIFF_SIZE {
+				self.mime = Some(self.options.check_type(&self.head)?);
+			}
+		}
+		return Ok(());
+	}
+
+	fn finish(self, ctx: &OSSContext, file: NamedTempFile) -> Result<FileBuf, ReceiveError> {
+		let mime = match self.mime {
+			Some(mime) => mime,
+			None => self.options.check_type(&self.head)?,
+		};
+
+		if let Err(algorithm) = self.verifier.verify() {
+			return Err(ReceiveError::Digest(format!("{} digest mismatch", algorithm)));
+		}
+
+		return Ok(FileBuf {
+			hash: self.hasher.finish(),
+			algorithm: self.options.hash.algorithm,
+			md5: self.md5.map(|md5| md5.finalize().to_vec()),
+			file,
+			size: self.size,
+			mime,
+			target: ctx.data_dir.clone(),
+			db: ctx.db.clone(),
+		});
+	}
 }
 
 // 一个请求只能上传一个文件，不支持用 Form 一次传多个，理由如下：
@@ -66,26 +314,310 @@ pub struct FileBuf {
 impl FileBuf {
 
 	// Create temp file in the same drive as data folder to avoid copy on rename.
-	async fn receive(ctx: &OSSContext, mut body: BodyStream) -> Result<FileBuf, axum::Error> {
-		let mut file = NamedTempFile::new_in(&ctx.buf_dir).unwrap();
-
-		// 非加密 Hash 速度快，但有恶意碰撞的风险，在允许公开上传时需要注意。
-		let mut hasher = Xxh3::new();
+	async fn receive(
+		ctx: &OSSContext,
+		mut body: BodyStream,
+		options: ReceiveOptions<'_>,
+	) -> Result<FileBuf, ReceiveError> {
+		let mut file = NamedTempFile::new_in(&ctx.buf_dir).map_err(ReceiveError::Io)?;
+		let mut inspector = Inspector::new(options);
 
+		// 先检查再写入，超出限制的部分不会落到磁盘上。
 		while let Some(chunk) = body.next().await {
-			let data = chunk?;
-			hasher.update(&data);
-			file.write(&data).unwrap();
+			let data = chunk.map_err(ReceiveError::Body)?;
+			inspector.update(&data)?;
+			file.write_all(&data).map_err(ReceiveError::Io)?;
+		}
+
+		return inspector.finish(ctx, file);
+	}
+
+	/// 把已经写好的文件（比如断点续传的分片）当作上传来检查，检查失败时文件会被删除。
+	async fn inspect(
+		ctx: &OSSContext,
+		path: PathBuf,
+		options: ReceiveOptions<'_>,
+	) -> Result<FileBuf, ReceiveError> {
+		let mut inspector = Inspector::new(options);
+		let file = NamedTempFile::from_parts(File::open(&path).map_err(ReceiveError::Io)?, TempPath::from_path(path));
+
+		let mut reader = ReaderStream::with_capacity(tokio::fs::File::from_std(file.reopen().map_err(ReceiveError::Io)?), 65536);
+		while let Some(chunk) = reader.next().await {
+			inspector.update(&chunk.map_err(ReceiveError::Io)?)?;
+		}
+
+		return inspector.finish(ctx, file);
+	}
+
+	/// 保存文件并写入索引，两者在同一个事务中，重命名失败时索引也会回滚。
+	///
+	/// 相同内容的对象已存在时保留原有的记录和文件，丢弃临时文件，返回原有的记录，第二项为 true。
+	/// 事务让并发上传相同内容的请求排队，只有第一个会移动文件。
+	/// 如果 `verify` 为 true 则逐字节比较两个文件，内容不同说明发生了 Hash 碰撞。
+	/// 比较可能要读很久，所以在事务之前、在阻塞线程里进行，不占用写锁。
+	pub async fn save(self, meta: UploadMeta, verify: bool) -> DbResult<(Object, bool)> {
+		let object = Object {
+			bucket: meta.bucket,
+			hash: self.hash,
+			size: self.size as i64,
+			mime: self.mime,
+			filename: meta.filename,
+			created_at: unix_time(SystemTime::now()),
+			uploader: meta.uploader,
+			algorithm: self.algorithm.name().to_owned(),
+		};
+
+		let (mut file, target) = (self.file, self.target.join(&object.hash));
+
+		let mut same = None;
+		if verify && target.is_file() {
+			let (compared, result) = compare_blocking(file, target.clone()).await?;
+			file = compared;
+			same = Some(result?);
 		}
 
-		let hash = hasher.digest128().to_be_bytes();
-		let hash = general_purpose::URL_SAFE_NO_PAD.encode(&hash[..15]);
+		// 闭包只执行一次，文件要么移到目标位置，要么留着在事务后比较。
+		let mut file = Some(file);
+		let mut conn = self.db.get()?;
+		let existing = conn.immediate_transaction(|conn| {
+			let inserted = diesel::insert_or_ignore_into(objects::table).values(&object).execute(conn)?;
+
+			// 记录存在但文件丢失（比如被手动删了）时，用新上传的补上，但记录仍是原有的。
+			let restore = inserted == 0 && !target.is_file();
+			if inserted > 0 || restore {
+				if let Some(file) = file.take() {
+					file.persist(&target)?;
+				}
+			}
+			if inserted > 0 {
+				return DbResult::Ok(None);
+			}
+			if !restore && same == Some(false) {
+				return Err(HashCollision(object.hash.clone()).into());
+			}
+
+			let existing = objects::table
+				.find((&object.bucket, &object.hash))
+				.select(Object::as_select())
+				.first(conn)?;
+			DbResult::Ok(Some(existing))
+		})?;
+
+		let Some(existing) = existing else {
+			log::debug!("New file saved, hash={}", object.hash);
+			return Ok((object, false));
+		};
+
+		// 事务前文件还不存在，是并发的请求刚保存的，这时记录已经不是本次写入的，事后比较也不用回滚。
+		if let (true, None, Some(file)) = (verify, same, file) {
+			let (_, result) = compare_blocking(file, target).await?;
+			if !result? {
+				return Err(HashCollision(existing.hash).into());
+			}
+		}
+		log::debug!("File already exists, hash={}", existing.hash);
+		return Ok((existing, true));
+	}
+}
+
+/// 在阻塞线程里比较临时文件和已保存的文件，把临时文件交还给调用方。
+async fn compare_blocking(
+	mut file: NamedTempFile,
+	target: PathBuf,
+) -> DbResult<(NamedTempFile, io::Result<bool>)> {
+	return Ok(tokio::task::spawn_blocking(move || {
+		let same = same_content(file.as_file_mut(), &target);
+		(file, same)
+	}).await?);
+}
+
+fn same_content(file: &mut File, other: &Path) -> io::Result<bool> {
+	let mut other = File::open(other)?;
+	if file.metadata()?.len() != other.metadata()?.len() {
+		return Ok(false);
+	}
+	file.rewind()?;
+
+	let (mut a, mut b) = (vec![0; 65536], vec![0; 65536]);
+	loop {
+		let n = file.read(&mut a)?;
+		if n == 0 {
+			return Ok(true);
+		}
+		other.read_exact(&mut b[..n])?;
+		if a[..n] != b[..n] {
+			return Ok(false);
+		}
+	}
+}
+
+/// 内容不同的文件有相同的 Hash，上传的文件不会被保存。
+#[derive(Debug)]
+pub struct HashCollision(pub String);
+
+impl fmt::Display for HashCollision {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Hash collision: {}", self.0)
+	}
+}
+
+impl Error for HashCollision {}
+
+/// 测试用的上下文，数据库和文件都在 `dir` 里，其它设置使用默认值。
+#[cfg(test)]
+pub fn test_context(dir: &Path) -> OSSContext {
+	let ctx = OSSContext {
+		data_dir: dir.join("files"),
+		buf_dir: dir.join("buffer"),
+		allow_anonymous: false,
+		presigner: Presigner::load(Some("test"), &dir.join("presign.key")).unwrap(),
+		session: Arc::new(SessionOptions::default()),
+		limiter: LoginLimiter::new(Default::default()),
+		compress: Arc::new(CompressOptions::default()),
+		db: crate::db::open(&dir.join("index.db")).unwrap(),
+		upload_locks: UploadLocks::default(),
+	};
+	std::fs::create_dir_all(&ctx.data_dir).unwrap();
+	std::fs::create_dir_all(&ctx.buf_dir).unwrap();
+	return ctx;
+}
+
+/// 把分块的请求体（没有 Content-Length）转为 BodyStream。
+#[cfg(test)]
+pub async fn test_body(chunks: &[&'static [u8]]) -> BodyStream {
+	use axum::extract::FromRequest;
 
-		return Ok(FileBuf { hash, file, target: ctx.data_dir.clone() });
+	let chunks = chunks.iter().map(|c| Ok::<_, io::Error>(axum::body::Bytes::from_static(c)));
+	let body = axum::body::Body::wrap_stream(futures::stream::iter(chunks.collect::<Vec<_>>()));
+	return BodyStream::from_request(axum::http::Request::new(body), &()).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::http::StatusCode;
+	use axum::response::IntoResponse;
+
+	use crate::context::{HashCollision, OCTET_STREAM, OSSContext, ReceiveError, ReceiveOptions, test_body, test_context, UploadMeta};
+	use crate::db::{DbResult, Object};
+	use crate::hash::HashOptions;
+	use crate::manual::save_error;
+
+	const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+	fn check(allowed: &[&str], claimed: &str, head: &[u8]) -> Result<String, ReceiveError> {
+		let allowed: Vec<String> = allowed.iter().map(|v| v.to_string()).collect();
+		let hash = HashOptions::default();
+		let options = ReceiveOptions {
+			claimed_type: Some(claimed),
+			allowed_types: &allowed,
+			hash: &hash,
+			digests: Vec::new(),
+			max_size: None,
+			md5: false,
+		};
+		return options.check_type(head);
+	}
+
+	#[test]
+	fn sniffed_type() {
+		assert_eq!(check(&["image/*"], "text/plain", PNG).unwrap(), "image/png");
+		assert!(matches!(check(&["image/*"], "image/jpeg", b"foobar"), Err(ReceiveError::Type(_))));
 	}
 
-	pub fn save(self) -> Result<File, PersistError> {
-		log::debug!("New file saved, hash={}", self.hash);
-		return self.file.persist(self.target.join(self.hash));
+	#[test]
+	fn active_types() {
+		let svg = b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>";
+		assert!(matches!(check(&["image/*"], "image/svg+xml", svg), Err(ReceiveError::Type(_))));
+		assert_eq!(check(&[], "image/svg+xml", svg).unwrap(), OCTET_STREAM);
+		assert_eq!(check(&[], "text/html", b"<script>").unwrap(), OCTET_STREAM);
+		assert_eq!(check(&["image/*", "image/svg+xml"], "image/svg+xml", svg).unwrap(), "image/svg+xml");
+	}
+
+	#[tokio::test]
+	async fn abort_too_large() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = test_context(dir.path());
+		let hash = HashOptions::default();
+		let options = ReceiveOptions {
+			claimed_type: None,
+			allowed_types: &[],
+			hash: &hash,
+			digests: Vec::new(),
+			max_size: Some(10),
+			md5: false,
+		};
+
+		let body = test_body(&[b"12345678", b"12345678"]).await;
+		let error = ctx.receive_file(body, options).await.err().unwrap();
+		assert!(matches!(error, ReceiveError::TooLarge));
+		assert_eq!(error.into_response().status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+		// 临时文件随之删除。
+		assert_eq!(std::fs::read_dir(&ctx.buf_dir).unwrap().count(), 0);
+	}
+
+	/// 接收并保存文件，文件名用于区分记录是哪次上传写入的。
+	async fn save(ctx: &OSSContext, data: &'static [u8], filename: &str, verify: bool) -> DbResult<(Object, bool)> {
+		let hash = HashOptions::default();
+		let options = ReceiveOptions {
+			claimed_type: None,
+			allowed_types: &[],
+			hash: &hash,
+			digests: Vec::new(),
+			max_size: None,
+			md5: false,
+		};
+		let body = test_body(&[data]).await;
+		let buf = ctx.receive_file(body, options).await.unwrap();
+		let meta = UploadMeta { bucket: "test".into(), filename: Some(filename.into()), uploader: None };
+		return buf.save(meta, verify).await;
+	}
+
+	#[tokio::test]
+	async fn save_duplicate() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = test_context(dir.path());
+		std::fs::create_dir_all(&ctx.data_dir).unwrap();
+
+		let (first, existed) = save(&ctx, PNG, "first.png", false).await.unwrap();
+		assert!(!existed);
+
+		// 返回原有的记录，临时文件被丢弃。
+		let (second, existed) = save(&ctx, PNG, "second.png", true).await.unwrap();
+		assert!(existed);
+		assert_eq!(second.hash, first.hash);
+		assert_eq!(second.filename.as_deref(), Some("first.png"));
+		assert_eq!(std::fs::read_dir(&ctx.buf_dir).unwrap().count(), 0);
+
+		// 文件丢失时用新上传的补上，记录不变。
+		let path = ctx.data_dir.join(&first.hash);
+		std::fs::remove_file(&path).unwrap();
+		let (restored, existed) = save(&ctx, PNG, "third.png", false).await.unwrap();
+		assert!(existed);
+		assert_eq!(restored.filename.as_deref(), Some("first.png"));
+		assert_eq!(std::fs::read(&path).unwrap(), PNG);
+	}
+
+	#[tokio::test]
+	async fn save_collision() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = test_context(dir.path());
+		std::fs::create_dir_all(&ctx.data_dir).unwrap();
+
+		// 把已保存的文件换成长度相同、内容不同的，模拟 Hash 碰撞。
+		let (object, _) = save(&ctx, PNG, "a.png", false).await.unwrap();
+		let path = ctx.data_dir.join(&object.hash);
+		let mut other = PNG.to_vec();
+		other[PNG.len() - 1] ^= 1;
+		std::fs::write(&path, &other).unwrap();
+
+		// 不比较时认为是同一个文件。
+		assert!(save(&ctx, PNG, "b.png", false).await.unwrap().1);
+
+		let error = save(&ctx, PNG, "c.png", true).await.err().unwrap();
+		assert!(error.is::<HashCollision>());
+		assert_eq!(save_error(error).status(), StatusCode::CONFLICT);
+		assert_eq!(std::fs::read(&path).unwrap(), other);
+		assert_eq!(std::fs::read_dir(&ctx.buf_dir).unwrap().count(), 0);
 	}
 }
@@ -0,0 +1,123 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use serde::Serialize;
+
+use crate::context::OCTET_STREAM;
+use crate::hash::HashAlgorithm;
+use crate::schema::objects;
+
+pub type DbPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// 数据库操作可能出现连接池、SQL 和文件系统等多种错误，调用方通常只需要记录日志。
+pub type DbResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+
+/// 对象的元数据索引，文件本身仍然以 Hash 为名保存在存储目录中。
+#[derive(Queryable, Selectable, Insertable, Serialize)]
+#[diesel(table_name = objects)]
+pub struct Object {
+	pub bucket: String,
+	pub hash: String,
+	pub size: i64,
+	pub mime: String,
+	pub filename: Option<String>,
+
+	/// Unix 时间戳，单位秒。
+	pub created_at: i64,
+
+	pub uploader: Option<String>,
+
+	/// 计算 Hash 的算法，见 `HashAlgorithm`。
+	pub algorithm: String,
+}
+
+/// SQLite 的这些设置是连接级别的，每个连接都要设置一遍。
+#[derive(Debug)]
+struct ConnectionOptions;
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for ConnectionOptions {
+	fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+		conn.batch_execute("PRAGMA busy_timeout = 5000; PRAGMA foreign_keys = ON;")
+			.map_err(diesel::r2d2::Error::QueryError)
+	}
+}
+
+/// 打开数据库并执行未应用的迁移，迁移脚本在编译时嵌入到程序中。
+pub fn open(file: &Path) -> DbResult<DbPool> {
+	let manager = ConnectionManager::<SqliteConnection>::new(file.to_string_lossy());
+	let pool = Pool::builder()
+		.connection_customizer(Box::new(ConnectionOptions))
+		.build(manager)?;
+
+	let mut conn = pool.get()?;
+
+	// WAL 模式是持久的，设置一次即可，它让读取不会被写入阻塞。
+	conn.batch_execute("PRAGMA journal_mode = WAL;")?;
+
+	for version in conn.run_pending_migrations(MIGRATIONS)? {
+		log::info!("Applied database migration {}", version);
+	}
+	return Ok(pool);
+}
+
+pub fn unix_time(time: SystemTime) -> i64 {
+	return time.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+}
+
+/// 把存储目录中已有但不在索引里的文件导入数据库，用于从没有索引的旧版本升级。
+///
+/// 只在该存储桶还没有任何记录时执行，所以正常启动不会扫描目录。
+/// 旧文件没有记录类型，与上传时一样根据文件头识别，无法识别的作为 octet-stream；Hash 则是旧版唯一的 XXH3。
+pub fn import_files(pool: &DbPool, bucket: &str, dir: &Path) -> DbResult<usize> {
+	let mut conn = pool.get()?;
+
+	let count: i64 = objects::table
+		.filter(objects::bucket.eq(bucket))
+		.count()
+		.get_result(&mut conn)?;
+	if count > 0 {
+		return Ok(0);
+	}
+
+	let mut records = Vec::new();
+	for entry in fs::read_dir(dir)? {
+		let entry = entry?;
+		let metadata = entry.metadata()?;
+		if !metadata.is_file() {
+			continue;
+		}
+		let hash = entry.file_name().to_string_lossy().into_owned();
+		let mime = match infer::get_from_path(entry.path())? {
+			Some(kind) => kind.mime_type(),
+			None => OCTET_STREAM,
+		};
+
+		records.push(Object {
+			bucket: bucket.to_owned(),
+			hash,
+			size: metadata.len() as i64,
+			mime: mime.to_owned(),
+			filename: None,
+			created_at: metadata.modified().map(unix_time).unwrap_or(0),
+			uploader: None,
+			algorithm: HashAlgorithm::Xxh3.name().to_owned(),
+		});
+	}
+
+	// 分批插入，避免超过 SQLite 单条语句的参数数量限制。
+	conn.immediate_transaction(|conn| {
+		for chunk in records.chunks(1000) {
+			diesel::insert_or_ignore_into(objects::table).values(chunk).execute(conn)?;
+		}
+		diesel::QueryResult::Ok(())
+	})?;
+	return Ok(records.len());
+}
@@ -0,0 +1,156 @@
+use axum::http::HeaderMap;
+use base64::{Engine as _, engine::general_purpose};
+use md5::Md5;
+use sha2::{Sha256, Sha512};
+use sha2::digest::DynDigest;
+
+/// 客户端在上传时附带的摘要，接收完后与实际内容比对，用于发现被截断或损坏的上传。
+///
+/// 请求体没有经过解码，所以 Content-Digest 和 Repr-Digest 都是对收到的字节计算。
+pub struct ExpectedDigest {
+	pub algorithm: &'static str,
+	value: Vec<u8>,
+}
+
+impl ExpectedDigest {
+
+	/// S3 的 x-amz-content-sha256 头是 Hex 编码的 SHA-256。
+	pub fn sha256(value: Vec<u8>) -> Self {
+		return ExpectedDigest { algorithm: "sha-256", value };
+	}
+}
+
+/// 支持的算法，名字来自 RFC 9530 的注册表，不安全的只支持 Content-MD5 所需的 md5。
+fn new_digest(algorithm: &str) -> Option<(&'static str, Box<dyn DynDigest + Send>)> {
+	match algorithm {
+		"sha-256" => Some(("sha-256", Box::new(Sha256::default()))),
+		"sha-512" => Some(("sha-512", Box::new(Sha512::default()))),
+		"md5" => Some(("md5", Box::new(Md5::default()))),
+		_ => None,
+	}
+}
+
+/// 从请求头中读取摘要，格式错误时返回 Err，不支持的算法则按规范忽略。
+///
+/// https://www.rfc-editor.org/rfc/rfc9530
+/// https://www.rfc-editor.org/rfc/rfc1864
+pub fn expected_digests(headers: &HeaderMap) -> Result<Vec<ExpectedDigest>, String> {
+	let mut digests = Vec::new();
+
+	for name in ["content-digest", "repr-digest"] {
+		for value in headers.get_all(name) {
+			let value = value.to_str().map_err(|_| format!("Invalid {}", name))?;
+			for member in value.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+				let (key, value) = member.split_once('=')
+					.ok_or_else(|| format!("Invalid {}: {}", name, member))?;
+
+				let algorithm = match new_digest(&key.trim().to_ascii_lowercase()) {
+					Some((algorithm, _)) => algorithm,
+					None => continue,
+				};
+
+				// 结构化字段的字节序列，可能还带有参数。
+				let value = value.split(';').next().unwrap().trim();
+				let value = value.strip_prefix(':').and_then(|v| v.strip_suffix(':'))
+					.ok_or_else(|| format!("Invalid {}: {}", name, member))?;
+
+				digests.push(ExpectedDigest { algorithm, value: decode(value)? });
+			}
+		}
+	}
+
+	if let Some(value) = headers.get("content-md5") {
+		let value = value.to_str().map_err(|_| "Invalid Content-MD5".to_owned())?;
+		digests.push(ExpectedDigest { algorithm: "md5", value: decode(value.trim())? });
+	}
+
+	return Ok(digests);
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, String> {
+	return general_purpose::STANDARD.decode(value).map_err(|_| format!("Invalid base64: {}", value));
+}
+
+/// 边接收边计算所有期望的摘要，相同的算法只算一次。
+pub struct DigestVerifier {
+	expected: Vec<ExpectedDigest>,
+	digests: Vec<(&'static str, Box<dyn DynDigest + Send>)>,
+}
+
+impl DigestVerifier {
+
+	pub fn new(expected: Vec<ExpectedDigest>) -> Self {
+		let mut digests: Vec<(&str, Box<dyn DynDigest + Send>)> = Vec::new();
+		for item in &expected {
+			if !digests.iter().any(|(name, _)| *name == item.algorithm) {
+				digests.extend(new_digest(item.algorithm));
+			}
+		}
+		return DigestVerifier { expected, digests };
+	}
+
+	pub fn update(&mut self, data: &[u8]) {
+		for (_, digest) in &mut self.digests {
+			digest.update(data);
+		}
+	}
+
+	/// 检查所有的摘要，返回第一个不匹配的算法名。
+	pub fn verify(self) -> Result<(), &'static str> {
+		let actual: Vec<_> = self.digests.into_iter()
+			.map(|(name, digest)| (name, digest.finalize()))
+			.collect();
+
+		for item in self.expected {
+			let (_, value) = actual.iter().find(|(name, _)| *name == item.algorithm).unwrap();
+			if **value != *item.value {
+				return Err(item.algorithm);
+			}
+		}
+		return Ok(());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::http::HeaderMap;
+
+	use crate::digest::{DigestVerifier, expected_digests};
+
+	const SHA256: &str = "sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:";
+
+	fn verify(name: &'static str, value: &str, body: &[u8]) -> Result<(), String> {
+		let mut headers = HeaderMap::new();
+		headers.append(name, value.try_into().unwrap());
+
+		let mut verifier = DigestVerifier::new(expected_digests(&headers)?);
+		verifier.update(body);
+		return verifier.verify().map_err(str::to_owned);
+	}
+
+	#[test]
+	fn content_digest() {
+		assert!(verify("Content-Digest", SHA256, b"hello").is_ok());
+		assert_eq!(verify("Content-Digest", SHA256, b"hell"), Err("sha-256".into()));
+	}
+
+	#[test]
+	fn multiple_and_unknown() {
+		let value = format!("unixsum=:AAAA:, {}, md5=:XUFAKrxLKna5cZ2REBfFkg==:", SHA256);
+		assert!(verify("Repr-Digest", &value, b"hello").is_ok());
+		assert_eq!(verify("Repr-Digest", &value, b"hello!"), Err("sha-256".into()));
+	}
+
+	#[test]
+	fn content_md5() {
+		assert!(verify("Content-MD5", "XUFAKrxLKna5cZ2REBfFkg==", b"hello").is_ok());
+		assert!(verify("Content-MD5", "XUFAKrxLKna5cZ2REBfFkg==", b"").is_err());
+	}
+
+	#[test]
+	fn malformed() {
+		assert!(verify("Content-Digest", "sha-256=LPJNul", b"").is_err());
+		assert!(verify("Content-Digest", "sha-256", b"").is_err());
+		assert!(verify("Content-MD5", "not base64!", b"").is_err());
+	}
+}
@@ -0,0 +1,178 @@
+use std::fmt::Write;
+
+use base64::{Engine as _, engine::general_purpose};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
+
+/// 计算对象 Hash 的算法，名字会随对象一起记录到索引中。
+///
+/// XXH3 最快但不抗碰撞，允许公开上传时，攻击者可以构造与已有对象 Hash 相同的文件，
+/// 由于相同 Hash 的对象只保存一份，这能用来阻止别人上传特定的文件。
+/// BLAKE3 和 SHA-256 是加密 Hash，前者更快，后者的兼容性更好。
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+	#[default]
+	Xxh3,
+	Blake3,
+	Sha256,
+}
+
+impl HashAlgorithm {
+
+	pub fn name(self) -> &'static str {
+		match self {
+			HashAlgorithm::Xxh3 => "xxh3",
+			HashAlgorithm::Blake3 => "blake3",
+			HashAlgorithm::Sha256 => "sha256",
+		}
+	}
+
+	/// 输出的字节数，Hash 只能截短不能加长。
+	pub fn output_size(self) -> usize {
+		match self {
+			HashAlgorithm::Xxh3 => 16,
+			HashAlgorithm::Blake3 | HashAlgorithm::Sha256 => 32,
+		}
+	}
+}
+
+/// Hash 转为字符串的方式，结果会作为文件名和 URL 的一部分。
+///
+/// Hex 比 base64 长 1/3，但只有小写字母和数字，适合大小写不敏感的文件系统，
+/// 见 `FileBuf::hash` 的说明。
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashEncoding {
+	#[default]
+	Base64,
+	Hex,
+}
+
+/// 存储桶的 Hash 配置，对应配置文件中的 `hash = { algorithm = "blake3", encoding = "hex" }`。
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct HashOptions {
+	pub algorithm: HashAlgorithm,
+	pub encoding: HashEncoding,
+
+	/// 截取 Hash 的前多少字节，默认 15，即 base64 的 20 个字符。
+	pub length: usize,
+}
+
+impl Default for HashOptions {
+	fn default() -> Self {
+		HashOptions {
+			algorithm: HashAlgorithm::Xxh3,
+			encoding: HashEncoding::Base64,
+			length: 15,
+		}
+	}
+}
+
+impl HashOptions {
+
+	/// 太短的 Hash 容易碰撞，这里要求至少 64 bit。
+	pub fn validate(&self) -> Result<(), String> {
+		let max = self.algorithm.output_size();
+		if (8..=max).contains(&self.length) {
+			return Ok(());
+		}
+		return Err(format!("Hash length of {} must be between 8 and {}", self.algorithm.name(), max));
+	}
+
+	pub fn hasher(&self) -> Hasher {
+		let inner = match self.algorithm {
+			HashAlgorithm::Xxh3 => HasherImpl::Xxh3(Box::default()),
+			HashAlgorithm::Blake3 => HasherImpl::Blake3(Box::default()),
+			HashAlgorithm::Sha256 => HasherImpl::Sha256(Sha256::new()),
+		};
+		return Hasher { inner, options: self.clone() };
+	}
+}
+
+enum HasherImpl {
+	Xxh3(Box<Xxh3>),
+	Blake3(Box<blake3::Hasher>),
+	Sha256(Sha256),
+}
+
+/// 按 `HashOptions` 计算并编码 Hash，接收上传时边读边更新。
+pub struct Hasher {
+	inner: HasherImpl,
+	options: HashOptions,
+}
+
+impl Hasher {
+
+	pub fn update(&mut self, data: &[u8]) {
+		match &mut self.inner {
+			HasherImpl::Xxh3(h) => h.update(data),
+			HasherImpl::Blake3(h) => { h.update(data); }
+			HasherImpl::Sha256(h) => h.update(data),
+		}
+	}
+
+	pub fn finish(self) -> String {
+		let digest = match self.inner {
+			HasherImpl::Xxh3(h) => h.digest128().to_be_bytes().to_vec(),
+			HasherImpl::Blake3(h) => h.finalize().as_bytes().to_vec(),
+			HasherImpl::Sha256(h) => h.finalize().to_vec(),
+		};
+		let digest = &digest[..self.options.length];
+
+		match self.options.encoding {
+			HashEncoding::Base64 => general_purpose::URL_SAFE_NO_PAD.encode(digest),
+			HashEncoding::Hex => digest.iter().fold(String::new(), |mut s, b| {
+				let _ = write!(s, "{:02x}", b);
+				s
+			}),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::hash::{HashAlgorithm, HashEncoding, HashOptions};
+
+	fn hash(algorithm: HashAlgorithm, encoding: HashEncoding, length: usize) -> String {
+		let mut hasher = HashOptions { algorithm, encoding, length }.hasher();
+		hasher.update(b"foo");
+		hasher.update(b"bar");
+		hasher.finish()
+	}
+
+	#[test]
+	fn default_is_compatible() {
+		let mut hasher = HashOptions::default().hasher();
+		hasher.update(b"foobar");
+
+		// 旧版本的 URL_SAFE_NO_PAD.encode(&xxh3_128.to_be_bytes()[..15])。
+		assert_eq!(hasher.finish(), "PJ4QJiiZf0Ssh7CxMcaZ");
+	}
+
+	#[test]
+	fn sha256_hex() {
+		let expected = "c3ab8ff13720e8ad9047dd39466b3c8974e592c2fa383d4a3960714caef0c4f2";
+		assert_eq!(hash(HashAlgorithm::Sha256, HashEncoding::Hex, 32), expected);
+		assert_eq!(hash(HashAlgorithm::Sha256, HashEncoding::Hex, 8), &expected[..16]);
+	}
+
+	#[test]
+	fn blake3_base64() {
+		let value = hash(HashAlgorithm::Blake3, HashEncoding::Base64, 30);
+		assert_eq!(value.len(), 40);
+		assert!(!value.contains(['+', '/', '=']));
+	}
+
+	#[test]
+	fn validate_length() {
+		let mut options = HashOptions { length: 16, ..Default::default() };
+		assert!(options.validate().is_ok());
+		options.length = 17;
+		assert!(options.validate().is_err());
+		options.length = 4;
+		assert!(options.validate().is_err());
+	}
+}
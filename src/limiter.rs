@@ -0,0 +1,404 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::http::HeaderMap;
+use serde::{Deserialize, Deserializer};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/*
+ * 登录的暴力破解防护，只记录失败的尝试，全部在内存里，重启后清空。
+ *
+ * 每个 IP 的前几次失败不受限制，之后每次失败等待的时间翻倍，
+ * 连续失败达到 max_attempts 次后锁定一段时间，登录成功则清零。
+ * 攻击者可能使用大量 IP，所以还限制了全局每分钟的失败次数，超过后所有人都要等到下一分钟。
+ *
+ * 检查时就预留一次尝试，验证完成前它按失败计算，所以并发的请求不能一起绕过限制。
+ * 验证密码很耗内存和 CPU，同时进行的验证数量也有上限，多出的请求排队等待。
+ */
+
+/// 全局失败次数的统计周期。
+const GLOBAL_WINDOW: Duration = Duration::from_secs(60);
+
+/// 登录限制的配置，对应配置文件中的 `[login]` 部分。
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct LoginOptions {
+	/// 不需要等待的失败次数，默认 3。
+	pub free_attempts: u32,
+
+	/// 超出免费次数后第一次的等待时间（秒），之后每次翻倍，默认 1。
+	pub base_delay: u64,
+
+	/// 连续失败多少次后锁定，默认 10。
+	pub max_attempts: u32,
+
+	/// 锁定的时长（秒），也是等待时间的上限，默认 15 分钟。
+	pub lockout: u64,
+
+	/// 所有 IP 每分钟合计允许失败的次数，默认 60。
+	pub global_max_per_minute: u32,
+
+	/// 可信的反向代理，支持 `10.0.0.0/8` 这样的 CIDR，
+	/// 只有来自这些地址的请求才会从 `client_ip_header` 读取客户端 IP。
+	#[serde(deserialize_with = "ip_nets")]
+	pub trusted_proxies: Vec<IpNet>,
+
+	/// 代理传递客户端 IP 的头，默认为 X-Forwarded-For，也可以是 X-Real-IP 等只有一个值的头。
+	pub client_ip_header: String,
+
+	/// 同时验证密码的最大数量，默认 2。
+	pub concurrent_hashes: usize,
+}
+
+impl Default for LoginOptions {
+	fn default() -> Self {
+		LoginOptions {
+			free_attempts: 3,
+			base_delay: 1,
+			max_attempts: 10,
+			lockout: 900,
+			global_max_per_minute: 60,
+			trusted_proxies: Vec::new(),
+			client_ip_header: "x-forwarded-for".into(),
+			concurrent_hashes: 2,
+		}
+	}
+}
+
+/// IP 地址段，没有前缀长度的视为单个地址。
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNet {
+	addr: IpAddr,
+	prefix: u8,
+}
+
+impl FromStr for IpNet {
+	type Err = String;
+
+	fn from_str(value: &str) -> Result<Self, Self::Err> {
+		let (addr, prefix) = match value.split_once('/') {
+			Some((addr, prefix)) => (addr, Some(prefix)),
+			None => (value, None),
+		};
+		let addr: IpAddr = addr.parse().map_err(|_| format!("Invalid IP address: {}", value))?;
+		let max = if addr.is_ipv4() { 32 } else { 128 };
+
+		let prefix = match prefix {
+			None => max,
+			Some(prefix) => prefix.parse().ok().filter(|p| *p <= max)
+				.ok_or_else(|| format!("Invalid prefix length: {}", value))?,
+		};
+		return Ok(IpNet { addr, prefix });
+	}
+}
+
+impl IpNet {
+
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		// IPv4 映射的 IPv6 地址（双栈监听时常见）按 IPv4 处理。
+		let ip = match ip {
+			IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+			_ => ip,
+		};
+		let (net, ip, bits) = match (self.addr, ip) {
+			(IpAddr::V4(a), IpAddr::V4(b)) => (u32::from(a) as u128, u32::from(b) as u128, 32),
+			(IpAddr::V6(a), IpAddr::V6(b)) => (u128::from(a), u128::from(b), 128),
+			_ => return false,
+		};
+		let shift = bits - self.prefix as u32;
+		return shift >= bits || net >> shift == ip >> shift;
+	}
+}
+
+fn ip_nets<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<IpNet>, D::Error> {
+	let list = Vec::<String>::deserialize(deserializer)?;
+	return list.iter().map(|v| v.parse().map_err(serde::de::Error::custom)).collect();
+}
+
+#[derive(Default)]
+struct Attempts {
+	failures: u32,
+	last_failure: Option<Instant>,
+
+	/// 已通过检查但还没有结果的尝试。
+	pending: u32,
+}
+
+#[derive(Default)]
+struct LimiterState {
+	clients: HashMap<IpAddr, Attempts>,
+	window_start: Option<Instant>,
+	window_failures: u32,
+	pending: u32,
+}
+
+/// 记录登录失败并计算需要等待的时间，可以在线程间共享。
+#[derive(Clone)]
+pub struct LoginLimiter {
+	options: Arc<LoginOptions>,
+	state: Arc<Mutex<LimiterState>>,
+	hashing: Arc<Semaphore>,
+}
+
+/// check 预留的一次尝试，用 failure 或 success 记录结果，直接丢弃（比如出错或客户端断开）则不计入。
+pub struct Attempt {
+	limiter: LoginLimiter,
+	ip: IpAddr,
+}
+
+impl Attempt {
+
+	pub fn failure(self, now: Instant) {
+		self.limiter.failure(self.ip, now);
+	}
+
+	pub fn success(self) {
+		self.limiter.success(self.ip);
+	}
+}
+
+impl Drop for Attempt {
+	fn drop(&mut self) {
+		let mut state = self.limiter.state.lock().unwrap();
+		state.pending -= 1;
+		if let Some(attempts) = state.clients.get_mut(&self.ip) {
+			attempts.pending -= 1;
+			if attempts.pending == 0 && attempts.failures == 0 {
+				state.clients.remove(&self.ip);
+			}
+		}
+	}
+}
+
+impl LoginLimiter {
+
+	pub fn new(options: LoginOptions) -> Self {
+		let hashing = Arc::new(Semaphore::new(options.concurrent_hashes.max(1)));
+		return LoginLimiter { options: Arc::new(options), state: Arc::default(), hashing };
+	}
+
+	/// 等待验证密码的许可，持有期间占用一个名额。
+	pub async fn hashing_permit(&self) -> SemaphorePermit<'_> {
+		return self.hashing.acquire().await.expect("The semaphore is never closed");
+	}
+
+	/// 确定客户端的 IP，来自可信代理的请求从右往左跳过代理自己的地址，
+	/// 第一个不可信的就是客户端，因为更左边的值可能是客户端伪造的。
+	pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+		let trusted = |ip: IpAddr| self.options.trusted_proxies.iter().any(|net| net.contains(ip));
+		if !trusted(peer) {
+			return peer;
+		}
+
+		let mut client = peer;
+		let values = headers.get_all(self.options.client_ip_header.as_str()).iter().rev();
+		for value in values {
+			let Ok(value) = value.to_str() else {
+				return client;
+			};
+			for item in value.rsplit(',') {
+				let Ok(ip) = item.trim().parse::<IpAddr>() else {
+					return client;
+				};
+				client = ip;
+				if !trusted(ip) {
+					return ip;
+				}
+			}
+		}
+		return client;
+	}
+
+	fn delay(&self, failures: u32) -> Duration {
+		let options = &self.options;
+		if failures >= options.max_attempts {
+			return Duration::from_secs(options.lockout);
+		}
+		if failures < options.free_attempts {
+			return Duration::ZERO;
+		}
+		let exponent = (failures - options.free_attempts).min(32);
+		let secs = options.base_delay.saturating_mul(1 << exponent);
+		return Duration::from_secs(secs.min(options.lockout));
+	}
+
+	/// 检查是否允许尝试登录，允许时预留一次尝试，不允许时返回需要等待的时间。
+	///
+	/// 进行中的尝试都当作失败，如果它们会让这次需要等待，就按刚刚失败计算等待时间。
+	pub fn check(&self, ip: IpAddr, now: Instant) -> Result<Attempt, Duration> {
+		let mut state = self.state.lock().unwrap();
+
+		let end = state.window_start.map(|start| start + GLOBAL_WINDOW).filter(|end| now < *end);
+		let window_failures = if end.is_some() { state.window_failures } else { 0 };
+		if window_failures + state.pending >= self.options.global_max_per_minute {
+			return Err(end.map_or(GLOBAL_WINDOW, |end| end - now));
+		}
+
+		if let Some(attempts) = state.clients.get(&ip) {
+			let failures = attempts.failures + attempts.pending;
+			let until = match attempts.last_failure {
+				_ if attempts.pending > 0 => now + self.delay(failures),
+				Some(last) => last + self.delay(failures),
+				None => now,
+			};
+			if now < until {
+				return Err(until - now);
+			}
+		}
+
+		state.pending += 1;
+		state.clients.entry(ip).or_default().pending += 1;
+		return Ok(Attempt { limiter: self.clone(), ip });
+	}
+
+	fn failure(&self, ip: IpAddr, now: Instant) {
+		let mut state = self.state.lock().unwrap();
+
+		if state.window_start.is_none_or(|start| now >= start + GLOBAL_WINDOW) {
+			state.window_start = Some(now);
+			state.window_failures = 0;
+		}
+		state.window_failures += 1;
+		if state.window_failures == self.options.global_max_per_minute {
+			log::warn!("Too many failed logins in the last minute, login is paused for all clients");
+		}
+
+		// 锁定结束后再失败，重新开始计数。
+		let lockout = Duration::from_secs(self.options.lockout);
+		let attempts = state.clients.entry(ip).or_default();
+		if attempts.last_failure.is_some_and(|last| now >= last + lockout) {
+			attempts.failures = 0;
+		}
+		attempts.failures += 1;
+		attempts.last_failure = Some(now);
+
+		let failures = attempts.failures;
+		if failures == self.options.max_attempts {
+			log::warn!("Locked out {} for {}s after {} failed logins", ip, self.options.lockout, failures);
+		}
+
+		// 防止伪造大量 IP 耗尽内存，过期的记录在表变大时清理。
+		if state.clients.len() > 10000 {
+			state.clients.retain(|_, a| a.pending > 0 || a.last_failure.is_some_and(|last| now < last + lockout));
+		}
+	}
+
+	/// 登录成功则清零，记录在没有进行中的尝试后删除。
+	fn success(&self, ip: IpAddr) {
+		if let Some(attempts) = self.state.lock().unwrap().clients.get_mut(&ip) {
+			attempts.failures = 0;
+			attempts.last_failure = None;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::IpAddr;
+	use std::time::{Duration, Instant};
+
+	use axum::http::HeaderMap;
+
+	use crate::limiter::{IpNet, LoginLimiter, LoginOptions};
+
+	fn ip(value: &str) -> IpAddr {
+		return value.parse().unwrap();
+	}
+
+	#[test]
+	fn ip_net() {
+		let net: IpNet = "10.0.0.0/8".parse().unwrap();
+		assert!(net.contains(ip("10.1.2.3")));
+		assert!(net.contains(ip("::ffff:10.1.2.3")));
+		assert!(!net.contains(ip("11.0.0.1")));
+
+		let net: IpNet = "fd00::/8".parse().unwrap();
+		assert!(net.contains(ip("fd12::1")));
+		assert!(!net.contains(ip("fe80::1")));
+
+		assert!("0.0.0.0/0".parse::<IpNet>().unwrap().contains(ip("1.2.3.4")));
+		assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+	}
+
+	#[test]
+	fn client_ip() {
+		let options = LoginOptions {
+			trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+			..Default::default()
+		};
+		let limiter = LoginLimiter::new(options);
+		let mut headers = HeaderMap::new();
+		headers.insert("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.0.0.2".parse().unwrap());
+
+		assert_eq!(limiter.client_ip(ip("10.0.0.1"), &headers), ip("1.2.3.4"));
+		assert_eq!(limiter.client_ip(ip("5.5.5.5"), &headers), ip("5.5.5.5"));
+		assert_eq!(limiter.client_ip(ip("10.0.0.1"), &HeaderMap::new()), ip("10.0.0.1"));
+	}
+
+	#[test]
+	fn backoff_and_lockout() {
+		let limiter = LoginLimiter::new(LoginOptions::default());
+		let client = ip("1.2.3.4");
+		let now = Instant::now();
+
+		for _ in 0..3 {
+			limiter.check(client, now).unwrap().failure(now);
+		}
+		assert_eq!(limiter.check(client, now).err(), Some(Duration::from_secs(1)));
+		assert!(limiter.check(ip("4.3.2.1"), now).is_ok());
+
+		for _ in 3..10 {
+			limiter.failure(client, now);
+		}
+		assert_eq!(limiter.check(client, now).err(), Some(Duration::from_secs(900)));
+
+		let later = now + Duration::from_secs(900);
+		limiter.check(client, later).unwrap().success();
+		assert!(limiter.check(client, now).is_ok());
+	}
+
+	#[test]
+	fn global_limit() {
+		let options = LoginOptions { global_max_per_minute: 5, ..Default::default() };
+		let limiter = LoginLimiter::new(options);
+		let now = Instant::now();
+
+		for i in 0..5 {
+			limiter.failure(ip(&format!("1.1.1.{}", i)), now);
+		}
+		assert_eq!(limiter.check(ip("2.2.2.2"), now + Duration::from_secs(10)).err(), Some(Duration::from_secs(50)));
+		assert!(limiter.check(ip("2.2.2.2"), now + Duration::from_secs(60)).is_ok());
+	}
+
+	#[test]
+	fn concurrent_attempts() {
+		let limiter = LoginLimiter::new(LoginOptions::default());
+		let client = ip("1.2.3.4");
+		let now = Instant::now();
+
+		// 结果出来之前，进行中的尝试按失败计算。
+		let pending: Vec<_> = (0..3).map(|_| limiter.check(client, now).unwrap()).collect();
+		assert_eq!(limiter.check(client, now).err(), Some(Duration::from_secs(1)));
+
+		// 丢弃的尝试不计入，记录的失败仍然生效。
+		let mut pending = pending.into_iter();
+		pending.next().unwrap().failure(now);
+		drop(pending);
+		assert!(limiter.check(client, now).is_ok());
+		assert_eq!(limiter.state.lock().unwrap().clients[&client].pending, 0);
+	}
+
+	#[test]
+	fn concurrent_global_limit() {
+		let options = LoginOptions { global_max_per_minute: 2, ..Default::default() };
+		let limiter = LoginLimiter::new(options);
+		let now = Instant::now();
+
+		let _a = limiter.check(ip("1.1.1.1"), now).unwrap();
+		let _b = limiter.check(ip("1.1.1.2"), now).unwrap();
+		assert_eq!(limiter.check(ip("1.1.1.3"), now).err(), Some(Duration::from_secs(60)));
+	}
+}
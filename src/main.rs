@@ -1,41 +1,75 @@
+#![allow(clippy::needless_return)]
+
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::error::Error;
 use std::fs::{self, OpenOptions};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use axum::{Router, Server};
-use axum::extract::State;
-use axum::http::{Request, StatusCode};
-use axum::middleware::{self, Next};
-use axum::response::{IntoResponse, Response};
-use axum::routing::post;
-use axum_extra::extract::cookie::CookieJar;
-use clap::{Parser, ValueHint};
+use axum::http::{HeaderName, HeaderValue};
+use axum::http::header::VARY;
+use axum::middleware;
+use axum::routing::{delete, get, post};
+use clap::{Parser, Subcommand, ValueHint};
 use log::{self, LevelFilter};
 use serde::Deserialize;
 use simplelog::{ColorChoice, ConfigBuilder, TerminalMode, TermLogger, WriteLogger};
 use tokio::runtime::Builder;
 use tokio::signal;
-use toml;
 use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tower_http::set_header::SetResponseHeaderLayer;
 
-use crate::api::login;
+use crate::compress::CompressOptions;
 use crate::context::OSSContext;
-use crate::manual::manual_bucket;
+use crate::api::{authorize, Guard, login, logout};
+use crate::limiter::{LoginLimiter, LoginOptions};
+use crate::manual::{BucketConfig, manual_bucket, ManualBucket};
+use crate::s3::{S3Config, s3_router};
+use crate::presign::{presign, Presigner};
+use crate::session::{list_sessions, revoke_session, SessionOptions};
 use crate::static_files::serve_static;
+use crate::token::{create_token, list_tokens, revoke_token, Scope};
+use crate::tus::{expire_uploads, EXPOSE_HEADERS, TusCorsLayer, UploadLocks};
+use crate::user::UserCommand;
 
+mod compress;
 mod context;
+mod db;
+mod digest;
+mod hash;
 mod range;
 mod api;
+mod limiter;
 mod manual;
+mod negotiate;
+mod presign;
+mod s3;
+mod schema;
+mod session;
+mod sigv4;
 mod static_files;
+mod token;
+mod tus;
+mod user;
 
 #[derive(Parser, Debug)]
 struct Args {
 	/// Specific config file path.
 	#[arg(long, value_hint = ValueHint::FilePath)]
 	config: Option<PathBuf>,
+
+	#[command(subcommand)]
+	command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+	/// Manage user accounts, then exit.
+	#[command(subcommand)]
+	User(UserCommand),
 }
 
 #[derive(Deserialize)]
@@ -48,14 +82,42 @@ struct AppConfig {
 	threads: Option<usize>,
 
 	bind: Option<SocketAddr>,
-	body_limit: Option<usize>,
 
+	/// 存储桶没有设置 max_size 时的默认上传大小限制（字节）。
+	body_limit: Option<u64>,
+
+	/// 已弃用，改用 `lwoss user add` 管理账户。没有任何用户时，启动时用它创建名为 admin 的管理员。
 	password: Option<String>,
+
+	/// 没有任何用户账户时允许匿名访问所有接口，只适合在本机试用，默认 false。
+	/// 为 false 时需要登录的接口在创建用户前都返回 503。
+	#[serde(default)]
+	allow_anonymous: bool,
+
+	/// 预签名 URL 的密钥，不设置则随机生成并保存在 `<data_dir>/presign.key`。
+	presign_secret: Option<String>,
+
 	data_dir: Option<PathBuf>,
+
+	#[serde(default)]
+	compression: CompressOptions,
+
+	#[serde(default)]
+	session: SessionOptions,
+
+	#[serde(default)]
+	login: LoginOptions,
+
+	/// 存储桶列表，没有配置时使用与旧版本兼容的默认图片桶。
+	#[serde(default, rename = "bucket")]
+	buckets: Vec<BucketConfig>,
+
+	/// 兼容 S3 的接口，只有配置了这部分才会启用。
+	s3: Option<S3Config>,
 }
 
-fn load_config(args: Args) -> AppConfig {
-	let config = match args.config {
+fn load_config(file: Option<PathBuf>) -> AppConfig {
+	let config = match file {
 		Some(file) => fs::read_to_string(file),
 		None => {
 			let mut file = env::current_dir().unwrap();
@@ -103,48 +165,168 @@ fn setup_logger(options: &AppConfig) -> Result<(), Box<dyn Error>> {
 	Ok(())
 }
 
+const CORS_VARY: &str = "origin, access-control-request-method, access-control-request-headers";
+
+/// 创建各个存储桶的路由，没有指定挂载点和存储目录的桶使用 `/s/<name>` 和 `<wd>/files/<name>`。
+/// 返回的表以名字为键，供 S3 API 使用。
+///
+/// 多个存储桶可以指定同一个存储目录，相同内容的文件只保存一份，删除时要检查所有这些桶的记录。
+fn mount_buckets(
+	ctx: &OSSContext,
+	wd: &Path,
+	buckets: Vec<BucketConfig>,
+	body_limit: Option<u64>,
+) -> (Router<OSSContext>, HashMap<String, ManualBucket>) {
+	let mut names = HashSet::new();
+	let mut states = Vec::new();
+
+	for mut bucket in buckets {
+		bucket.max_size = bucket.max_size.or(body_limit);
+		if !names.insert(bucket.name.clone()) {
+			panic!("Duplicate bucket name: {}", bucket.name);
+		}
+		let mount = bucket.mount.clone().unwrap_or_else(|| format!("/s/{}", bucket.name));
+		let storage = bucket.storage.clone().unwrap_or_else(|| wd.join("files").join(&bucket.name));
+		fs::create_dir_all(&storage).unwrap();
+
+		match db::import_files(&ctx.db, &bucket.name, &storage) {
+			Ok(0) => {}
+			Ok(count) => log::info!("Imported {} existing files into bucket {}", count, bucket.name),
+			Err(e) => log::error!("Failed to import existing files: {}", e),
+		}
+
+		let mut bucket_ctx = ctx.clone();
+		bucket_ctx.data_dir = storage;
+
+		states.push((mount, ManualBucket::new(bucket_ctx, bucket)));
+	}
+
+	// 配置中的路径可能写法不同，比较规范化之后的。
+	let canonical = |state: &ManualBucket| {
+		fs::canonicalize(&state.ctx.data_dir).unwrap_or_else(|_| state.ctx.data_dir.clone())
+	};
+	let mut storages: HashMap<PathBuf, Vec<String>> = HashMap::new();
+	for (_, state) in &states {
+		storages.entry(canonical(state)).or_default().push(state.config.name.clone());
+	}
+
+	let mut bucket_routes = Router::new();
+	let mut s3_buckets = HashMap::new();
+
+	for (mount, mut state) in states {
+		state.storage_buckets = storages[&canonical(&state)].as_slice().into();
+
+		log::info!("Bucket {} is mounted at {}", state.config.name, mount);
+		s3_buckets.insert(state.config.name.clone(), state.clone());
+		bucket_routes = bucket_routes.nest(&mount, manual_bucket(state));
+	}
+
+	return (bucket_routes, s3_buckets);
+}
+
 async fn run(config: AppConfig) {
 	let wd = config.data_dir.unwrap_or("data".into());
 
+	fs::create_dir_all(&wd).unwrap();
+	let db = db::open(&wd.join("index.db")).expect("Unable to open database");
+
+	if let Some(password) = &config.password {
+		user::bootstrap_admin(&db, password).expect("Unable to create the admin user");
+	}
+	if user::count_users(&db).expect("Unable to count users") == 0 {
+		if config.allow_anonymous {
+			log::warn!("No user accounts, all APIs are public until one is created with `lwoss user add`");
+		} else {
+			log::warn!("No user accounts, APIs that require login are unavailable. Use `lwoss user add` to create one");
+		}
+	}
+
+	let presigner = Presigner::load(config.presign_secret.as_deref(), &wd.join("presign.key"))
+		.expect("Unable to load presign key");
+
 	let ctx = OSSContext {
 		data_dir: wd.join("files"),
 		buf_dir: wd.join("buffer"),
-		password: config.password.clone(),
+		allow_anonymous: config.allow_anonymous,
+		presigner,
+		session: Arc::new(config.session),
+		limiter: LoginLimiter::new(config.login),
+		compress: Arc::new(config.compression),
+		db,
+		upload_locks: UploadLocks::default(),
 	};
 
-	fs::create_dir_all(&ctx.data_dir).unwrap();
 	fs::create_dir_all(&ctx.buf_dir).unwrap();
 
-	let mut admin_routes = Router::new()
-		.route("/api", post(login));
+	let mut buckets = config.buckets;
+	if buckets.is_empty() {
+		buckets.push(BucketConfig::default_image(&wd));
+	}
+
+	let (mut bucket_routes, s3_buckets) = mount_buckets(&ctx, &wd, buckets, config.body_limit);
+
+	tokio::spawn(expire_uploads(ctx.clone()));
 
-	if let Some(password) = config.password {
-		admin_routes = admin_routes.route_layer(middleware::from_fn_with_state(password, auth));
+	if let Some(s3) = config.s3 {
+		let mount = s3.mount.clone().unwrap_or_else(|| "/s3".into());
+		log::info!("S3 API is mounted at {}", mount);
+		bucket_routes = bucket_routes.nest(&mount, s3_router(s3, s3_buckets));
+		tokio::spawn(s3::expire_uploads(ctx.clone()));
 	}
 
-	let app = admin_routes
-		.merge(serve_static("web/build".into(), Some("web/build/index.html".into())))
-		.nest("/s/image", manual_bucket(ctx.clone()))
-		.with_state(ctx)
-		.layer(CorsLayer::new()
+	let mut admin_routes = Router::new()
+		.route("/api/presign", post(presign))
+		.route("/api/sessions", get(list_sessions))
+		.route("/api/sessions/:id", delete(revoke_session))
+		.route("/api/tokens", get(list_tokens).post(create_token))
+		.route("/api/tokens/:id", delete(revoke_token));
+
+	let guard = Guard { ctx: ctx.clone(), scope: Scope::Admin, bucket: None, presign: false };
+	admin_routes = admin_routes.route_layer(middleware::from_fn_with_state(guard, authorize));
+
+	// 登录和注销不能放在需要登录的路由里。
+	let session_routes = Router::new()
+		.route("/api", post(login))
+		.route("/api/logout", post(logout));
+
+	let app: Router = admin_routes
+		.merge(session_routes)
+		.merge(serve_static("web/build".into(), Some("web/build/index.html".into()), ctx.compress.clone()))
+		.merge(bucket_routes)
+		.with_state(ctx);
+
+	let app = app
+		.layer(TusCorsLayer(CorsLayer::new()
 			.allow_origin(AllowOrigin::mirror_request())
 			.allow_headers(Any)
-			.allow_methods(Any));
-
-	// https://github.com/tokio-rs/axum/issues/1110
-	// if let Some(size) = config.body_limit {
-	// 	app = app.layer(RequestBodyLimitLayer::new(size));
-	// }
+			.allow_methods(Any)
+			.expose_headers(EXPOSE_HEADERS.map(HeaderName::from_static))
+			.vary(Vec::new())))
+		// CorsLayer 会覆盖内层设置的 Vary（比如 Accept-Encoding），所以改为在外层追加。
+		.layer(SetResponseHeaderLayer::appending(VARY, HeaderValue::from_static(CORS_VARY)));
 
 	let addr = config.bind.unwrap_or(SocketAddr::from(([127, 0, 0, 1], 6319)));
 	log::info!("LW-OSS is listening on {}", addr);
-	Server::bind(&addr).serve(app.into_make_service()).await.unwrap();
+	Server::bind(&addr).serve(app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
 }
 
 fn main() {
-	let config = load_config(Args::parse());
+	let args = Args::parse();
+	let config = load_config(args.config);
 	setup_logger(&config).expect("Unable to create logger");
 
+	if let Some(Command::User(command)) = args.command {
+		let wd = config.data_dir.unwrap_or("data".into());
+		fs::create_dir_all(&wd).unwrap();
+		let db = db::open(&wd.join("index.db")).expect("Unable to open database");
+
+		if let Err(e) = user::run_command(&db, command) {
+			eprintln!("{}", e);
+			std::process::exit(1);
+		}
+		return;
+	}
+
 	let threads = config.threads.unwrap_or(1);
 	let mut tokio = if threads == 1 {
 		Builder::new_current_thread()
@@ -159,20 +341,6 @@ fn main() {
 	tokio.enable_all().build().unwrap().block_on(run(config));
 }
 
-async fn auth<B>(
-	State(password): State<String>,
-	jar: CookieJar,
-	request: Request<B>,
-	next: Next<B>,
-) -> Response {
-	if let Some(cookie) = jar.get("password") {
-		if cookie.value() == password {
-			return next.run(request).await;
-		}
-	}
-	return StatusCode::FORBIDDEN.into_response();
-}
-
 // https://github.com/tokio-rs/axum/blob/main/examples/graceful-shutdown
 #[allow(dead_code)]
 async fn shutdown_signal() {
@@ -200,3 +368,90 @@ async fn shutdown_signal() {
 
 	println!("Signal received, starting graceful shutdown...");
 }
+
+#[cfg(test)]
+mod tests {
+	use axum::body::Body;
+	use axum::http::{Request, StatusCode};
+	use axum::http::header::CONTENT_TYPE;
+	use axum::Router;
+	use tower::ServiceExt;
+
+	use crate::context::test_context;
+	use crate::manual::BucketConfig;
+	use crate::mount_buckets;
+
+	const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+	fn bucket(name: &str) -> BucketConfig {
+		let mut config = BucketConfig::default_image("".as_ref());
+		config.name = name.into();
+		config.storage = None;
+		return config;
+	}
+
+	async fn status(app: &Router, request: Request<Body>) -> StatusCode {
+		return app.clone().oneshot(request).await.unwrap().status();
+	}
+
+	#[test]
+	#[should_panic(expected = "Duplicate bucket name: image")]
+	fn duplicate_names() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = test_context(dir.path());
+		let _ = mount_buckets(&ctx, dir.path(), vec![bucket("image"), bucket("image")], None);
+	}
+
+	#[test]
+	fn defaults() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = test_context(dir.path());
+		let limited = BucketConfig { max_size: Some(10), ..bucket("limited") };
+		let (_, buckets) = mount_buckets(&ctx, dir.path(), vec![bucket("image"), limited], Some(1000));
+
+		let image = &buckets["image"];
+		assert_eq!(image.ctx.data_dir, dir.path().join("files/image"));
+		assert!(image.ctx.data_dir.is_dir());
+		assert_eq!(image.config.max_size, Some(1000));
+		assert_eq!(buckets["limited"].config.max_size, Some(10));
+		assert_eq!(&*image.storage_buckets, ["image"]);
+	}
+
+	#[test]
+	fn shared_storage() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = test_context(dir.path());
+		let shared = |name| BucketConfig { storage: Some(dir.path().join("shared")), ..bucket(name) };
+		// 写法不同的同一个目录。
+		let same = BucketConfig { storage: Some(dir.path().join("other/../shared")), ..bucket("c") };
+		let configs = vec![shared("a"), shared("b"), same, bucket("d")];
+		let (_, buckets) = mount_buckets(&ctx, dir.path(), configs, None);
+
+		assert_eq!(&*buckets["a"].storage_buckets, ["a", "b", "c"]);
+		assert_eq!(&*buckets["c"].storage_buckets, ["a", "b", "c"]);
+		assert_eq!(&*buckets["d"].storage_buckets, ["d"]);
+	}
+
+	#[tokio::test]
+	async fn independent_buckets() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut ctx = test_context(dir.path());
+		ctx.allow_anonymous = true;
+		let custom = BucketConfig { mount: Some("/docs".into()), ..bucket("docs") };
+		let (routes, _) = mount_buckets(&ctx, dir.path(), vec![bucket("image"), custom], None);
+		let app: Router = routes.with_state(ctx);
+
+		let request = Request::post("/s/image").header(CONTENT_TYPE, "image/png").body(Body::from(PNG)).unwrap();
+		assert_eq!(status(&app, request).await, StatusCode::OK);
+
+		let entry = std::fs::read_dir(dir.path().join("files/image")).unwrap().next().unwrap().unwrap();
+		let hash = entry.file_name().into_string().unwrap();
+
+		// 对象只属于上传的桶，即使另一个桶的类型限制相同。
+		let get = |uri: String| Request::get(uri).body(Body::empty()).unwrap();
+		assert_eq!(status(&app, get(format!("/s/image/{}", hash))).await, StatusCode::OK);
+		assert_eq!(status(&app, get(format!("/docs/{}", hash))).await, StatusCode::NOT_FOUND);
+		assert_eq!(status(&app, get(format!("/s/docs/{}", hash))).await, StatusCode::NOT_FOUND);
+		assert_eq!(std::fs::read_dir(dir.path().join("files/docs")).unwrap().count(), 0);
+	}
+}
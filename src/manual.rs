@@ -1,16 +1,33 @@
+use std::collections::HashMap;
+use std::error::Error;
 use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
 
-use axum::{Json, response::IntoResponse, Router};
-use axum::extract::{BodyStream, Path, State};
+use axum::{Extension, Json, response::IntoResponse, Router};
+use axum::extract::{BodyStream, Path, Query, State};
 use axum::http::{HeaderMap, HeaderName, StatusCode};
-use axum::http::header::CACHE_CONTROL;
+use axum::http::header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE, VARY, X_CONTENT_TYPE_OPTIONS};
 use axum::http::HeaderValue;
+use axum::middleware;
 use axum::response::Response;
-use axum::routing::{get, post};
-use log;
+use axum::routing::{delete, get, post};
+use diesel::prelude::*;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Deserializer, Serialize};
 
-use crate::context::{OSSContext, UploadVO};
+use crate::api::{authorize, Guard, Identity};
+use crate::context::{
+	HashCollision, is_allowed_type, OCTET_STREAM, OSSContext, ReceiveError, ReceiveOptions, UploadMeta, UploadVO,
+};
+use crate::db::{DbResult, Object};
+use crate::digest::expected_digests;
+use crate::hash::HashOptions;
+use crate::negotiate::preferred_types;
 use crate::range::{FileCache, FileRangeReadr, send_range};
+use crate::schema::{objects, s3_keys, variants};
+use crate::token::Scope;
+use crate::tus::tus_routes;
 
 /*
  * 【文件的多层封装】
@@ -35,8 +52,9 @@ use crate::range::{FileCache, FileRangeReadr, send_range};
  * 然后上传同一视频的多个版本。
  *
  * <h2>安全性</h2>
- * 目前的实现未检查文件的路径和内容，存在恶意上传的风险，请用于可信来源！
- * file-type 之类的库会更好些，但即便检查了文件头，仍不能保证内容有效，除非完整地解码。
+ * 上传时会检查文件头（infer 库）并与允许的类型列表比对，能挡住 HTML、可执行文件等。
+ * 但即便检查了文件头，仍不能保证内容有效，除非完整地解码；SVG 之类的文本格式
+ * 也无法识别，只能相信 Content-Type，所以允许 SVG 的存储桶仍应只对可信来源开放。
  *
  * <h2>原始版本</h2>
  * 以后要想做自动转码会用到，把旧版手动上传的转成新编码，这需要判断出那个
@@ -46,49 +64,1283 @@ use crate::range::{FileCache, FileRangeReadr, send_range};
  * 需要注意视频转码是有损的，这意味着难以检测上传的多个版本是否包含相同的内容，
  * 如果上传了不同的视频作为变体，则不同的浏览器可能访问到不同的内容。
  */
-
-pub fn manual_bucket<OS>(ctx: OSSContext) -> Router<OS> {
-	return Router::new()
-		.route("/:hash", get(download))
+pub fn manual_bucket<OS>(state: ManualBucket) -> Router<OS> {
+	let mut write_routes = Router::new()
+		.route("/:hash", post(upload_variant))
 		.route("/", post(upload))
-		.with_state(ManualBucket { ctx: ctx.clone() });
+		.merge(tus_routes());
+
+	let mut read_routes = Router::new()
+		.route("/:hash", get(download));
+
+	let mut list_routes = Router::new()
+		.route("/", get(list));
+
+	let mut delete_routes = Router::new()
+		.route("/:hash", delete(remove));
+
+	let guard = |scope, presign| {
+		let bucket = Some(state.config.name.clone());
+		let guard = Guard { ctx: state.ctx.clone(), scope, bucket, presign };
+		middleware::from_fn_with_state(guard, authorize)
+	};
+	let policy = &state.config.access;
+	if policy.read == Access::Authenticated {
+		read_routes = read_routes.route_layer(guard(Scope::Read, true));
+	}
+	if policy.list == Access::Authenticated {
+		list_routes = list_routes.route_layer(guard(Scope::Read, false));
+	}
+	if policy.upload == Access::Authenticated {
+		write_routes = write_routes.route_layer(guard(Scope::Upload, true));
+	}
+	if policy.delete == Access::Authenticated {
+		delete_routes = delete_routes.route_layer(guard(Scope::Delete, false));
+	}
+
+	return read_routes
+		.merge(write_routes)
+		.merge(list_routes)
+		.merge(delete_routes)
+		.with_state(state);
 }
 
+/// 存储桶的配置，对应配置文件中的 `[[bucket]]`，每个存储桶有独立的目录和规则。
+#[derive(Clone, Deserialize)]
+pub struct BucketConfig {
+	/// 存储桶的名字，用于在索引中区分不同桶的对象，不能重复。
+	pub name: String,
+
+	/// 挂载的路径，默认为 `/s/<name>`。
+	pub mount: Option<String>,
+
+	/// 文件的存储目录，默认为 `<data_dir>/files/<name>`。
+	/// 上传的临时文件是通过重命名移过来的，所以它必须与 data_dir 在同一个文件系统上。
+	pub storage: Option<PathBuf>,
+
+	/// 允许上传的类型，比如 `["image/*"]`，为空则不限制，见 `ReceiveOptions`。
+	/// `text/*` 和 SVG 等能执行脚本的类型必须明确列出，否则按 octet-stream 保存。
+	#[serde(default)]
+	pub allowed_types: Vec<String>,
+
+	/// 下载时的 Cache-Control，设置后用于所有的响应。
+	///
+	/// 默认情况下，没有变体的对象（包括变体自己）内容由 URL 中的 Hash 决定，可以永久缓存；
+	/// 有变体的对象会在其中选择，所以每次都要向服务器验证 ETag。
+	/// 注意给已被缓存的对象添加变体后，客户端在缓存过期前仍会使用原文件，应在上传后尽快添加。
+	pub cache_control: Option<String>,
+
+	/// 上传文件的最大字节数，不设置则使用全局的 body_limit，都没有则不限制。
+	pub max_size: Option<u64>,
+
+	/// 各类操作是否需要登录，见 AccessPolicy。
+	#[serde(default)]
+	pub access: AccessPolicy,
+
+	/// 计算对象 Hash 的算法、长度和编码，修改后旧的对象仍然能访问，
+	/// 但相同内容的文件再次上传会得到新的 Hash。
+	#[serde(default)]
+	pub hash: HashOptions,
+
+	/// 断点续传的上传在创建后多少秒过期，默认为一天，见 tus.rs。
+	#[serde(default = "default_tus_expiration")]
+	pub tus_expiration: u64,
+
+	/// 上传已存在的对象时逐字节比较内容以发现 Hash 碰撞，需要多读一遍已有的文件。
+	#[serde(default)]
+	pub verify_duplicates: bool,
+
+	/// 选择变体的方式，为 None 时不做选择，总是返回原始文件。
+	pub codec_detect: Option<CodecDetect>,
+
+	/// 编码的偏好顺序，排在前面的优先，不在列表里的变体不会被选中。
+	#[serde(default)]
+	pub codecs: Vec<String>,
+}
+
+fn default_tus_expiration() -> u64 {
+	return 86400;
+}
+
+impl BucketConfig {
+
+	/// 没有配置任何存储桶时使用的默认桶，与旧版本的目录结构兼容。
+	pub fn default_image(data_dir: &std::path::Path) -> Self {
+		return BucketConfig {
+			name: "image".into(),
+			mount: None,
+			storage: Some(data_dir.join("files")),
+			allowed_types: vec!["image/*".into()],
+			cache_control: None,
+			max_size: None,
+			access: AccessPolicy::default(),
+			hash: HashOptions::default(),
+			tus_expiration: default_tus_expiration(),
+			verify_duplicates: false,
+			codec_detect: None,
+			codecs: Vec::new(),
+		};
+	}
+}
+
+/// 一类操作的访问要求。
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+	Public,
+
+	/// 需要登录的会话或有相应权限的 API 令牌，下载和上传还接受预签名的 URL。
+	Authenticated,
+}
+
+/// 存储桶的访问策略，对应配置文件中的 `[bucket.access]`，比如 `read = "authenticated"`。
+///
+/// 默认只有下载是公开的，上传、删除和列出对象都需要登录。
+/// 没有任何用户账户时无法登录，需要登录的操作返回 503，除非配置了 allow_anonymous。
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct AccessPolicy {
+	pub read: Access,
+	pub list: Access,
+	pub upload: Access,
+	pub delete: Access,
+}
+
+impl Default for AccessPolicy {
+	fn default() -> Self {
+		AccessPolicy {
+			read: Access::Public,
+			list: Access::Authenticated,
+			upload: Access::Authenticated,
+			delete: Access::Authenticated,
+		}
+	}
+}
+
+/// 客户端声明其支持的编码的方式，值是逗号分隔的编码名，比如 `av1,hevc`。
+/// 使用请求头时响应会加上对应的 Vary，而查询参数本身就是 URL 的一部分，无需 Vary。
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum CodecDetect {
 	Param(String),
-	Header(HeaderName),
+	Header(#[serde(deserialize_with = "header_name")] HeaderName),
+}
+
+fn header_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HeaderName, D::Error> {
+	let name = String::deserialize(deserializer)?;
+	return HeaderName::try_from(name).map_err(serde::de::Error::custom);
 }
 
+/// 存储桶的状态，其中 ctx.data_dir 是该存储桶自己的存储目录。
 #[derive(Clone)]
 pub struct ManualBucket {
-	// detect: CodecDetect,
-	// codecs: Vec<(String, String)>,
+	pub config: Arc<BucketConfig>,
+
+	/// 变体的 URL 使用的 Cache-Control，见 BucketConfig.cache_control。
+	cache_control: HeaderValue,
+
+	/// 可能在变体中选择的 URL 使用的 Cache-Control。
+	negotiated_cache_control: HeaderValue,
 	pub ctx: OSSContext,
+
+	/// 使用同一存储目录的存储桶（包括自己），删除文件前要检查它们的记录。
+	pub storage_buckets: Arc<[String]>,
 }
 
-async fn upload(state: State<ManualBucket>, body: BodyStream) -> Response {
-	let buf = state.ctx.receive_file(body).await.unwrap();
-	let hash = buf.hash.clone();
-	log::trace!("New file saved, hash={}", hash);
+/// 一个变体文件，与原始文件属于同一个对象，本身也是普通的对象。
+///
+/// 有 codec 的变体按客户端声明的编码选择（视频），没有的按 Accept 头选择格式（图片）。
+#[derive(Queryable, Serialize)]
+pub struct Variant {
+	pub hash: String,
+	pub codec: Option<String>,
+	pub mime: String,
+	pub size: i64,
+}
+
+impl ManualBucket {
+
+	/// ctx.data_dir 需要先设置为该存储桶的存储目录，配置无效时 panic。
+	pub fn new(ctx: OSSContext, config: BucketConfig) -> Self {
+		if let Err(message) = config.hash.validate() {
+			panic!("Invalid hash options of bucket {}: {}", config.name, message);
+		}
+
+		let (exact, negotiated) = match config.access.read {
+			Access::Public => (IMMUTABLE, NEGOTIATED),
+			Access::Authenticated => (PRIVATE_IMMUTABLE, PRIVATE_NEGOTIATED),
+		};
+		let header = |default| {
+			let value = config.cache_control.as_deref().unwrap_or(default);
+			HeaderValue::from_str(value).expect("Invalid cache_control")
+		};
+
+		return ManualBucket {
+			ctx,
+			cache_control: header(exact),
+			negotiated_cache_control: header(negotiated),
+			storage_buckets: Arc::new([config.name.clone()]),
+			config: Arc::new(config),
+		};
+	}
+
+	/// 读取对象的变体列表，类型和大小来自变体自己的对象记录。
+	fn load_variants(&self, hash: &str) -> DbResult<Vec<Variant>> {
+		let mut conn = self.ctx.db.get()?;
+
+		let on = objects::bucket.eq(variants::bucket).and(objects::hash.eq(variants::hash));
+		let list = variants::table
+			.inner_join(objects::table.on(on))
+			.filter(variants::bucket.eq(&self.config.name))
+			.filter(variants::object.eq(hash))
+			.select((variants::hash, variants::codec, objects::mime, objects::size))
+			.load(&mut conn)?;
+
+		return Ok(list);
+	}
+
+	/// 把已保存的对象添加为变体，同一编码（没有编码时则是同一类型）的旧变体会被替换。
+	fn add_variant(&self, hash: &str, variant: &Object, codec: Option<String>) -> DbResult<()> {
+		let mut conn = self.ctx.db.get()?;
+
+		conn.immediate_transaction(|conn| {
+			let same = variants::table
+				.filter(variants::bucket.eq(&self.config.name))
+				.filter(variants::object.eq(hash));
+
+			match &codec {
+				Some(codec) => {
+					diesel::delete(same.filter(variants::codec.eq(codec))).execute(conn)?;
+				}
+				None => {
+					let same_mime = objects::table
+						.filter(objects::bucket.eq(&self.config.name))
+						.filter(objects::mime.eq(&variant.mime))
+						.select(objects::hash);
+					diesel::delete(same.filter(variants::codec.is_null()).filter(variants::hash.eq_any(same_mime)))
+						.execute(conn)?;
+				}
+			}
+
+			diesel::replace_into(variants::table)
+				.values((
+					variants::bucket.eq(&self.config.name),
+					variants::object.eq(hash),
+					variants::hash.eq(&variant.hash),
+					variants::codec.eq(&codec),
+				))
+				.execute(conn)?;
+
+			diesel::QueryResult::Ok(())
+		})?;
+		return Ok(());
+	}
+
+	/// 按创建时间从新到旧列出对象，变体只出现在所属对象的 `variants` 里。
+	///
+	/// 用 (created_at, hash) 作为游标而不是 OFFSET，翻页时有新的上传也不会重复或遗漏，
+	/// 而且每页都能直接从索引定位，不会越翻越慢。
+	fn list(&self, query: &ListQuery, cursor: Option<(i64, &str)>) -> DbResult<Vec<ObjectVO>> {
+		let mut conn = self.ctx.db.get()?;
+		let bucket = &self.config.name;
+
+		let is_variant = variants::table
+			.filter(variants::bucket.eq(bucket))
+			.filter(variants::hash.eq(objects::hash));
+
+		let mut select = objects::table
+			.filter(objects::bucket.eq(bucket))
+			.filter(diesel::dsl::not(diesel::dsl::exists(is_variant)))
+			.select(Object::as_select())
+			.order((objects::created_at.desc(), objects::hash.desc()))
+			.limit(query.limit())
+			.into_boxed();
+
+		if let Some((created_at, hash)) = cursor {
+			select = select.filter(objects::created_at.lt(created_at)
+				.or(objects::created_at.eq(created_at).and(objects::hash.lt(hash.to_owned()))));
+		}
+		if let Some(since) = query.since {
+			select = select.filter(objects::created_at.ge(since));
+		}
+		match query.r#type.as_deref().map(|t| (t, t.strip_suffix("/*"))) {
+			None | Some(("*/*", _)) => {}
+			Some((_, Some(top))) => {
+				let escaped = top.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+				select = select.filter(objects::mime.like(format!("{}/%", escaped)).escape('\\'));
+			}
+			Some((mime, None)) => select = select.filter(objects::mime.eq(mime.to_owned())),
+		}
+
+		let objects: Vec<Object> = select.load(&mut conn)?;
+
+		let on = objects::bucket.eq(variants::bucket).and(objects::hash.eq(variants::hash));
+		let variants: Vec<(String, Variant)> = variants::table
+			.inner_join(objects::table.on(on))
+			.filter(variants::bucket.eq(bucket))
+			.filter(variants::object.eq_any(objects.iter().map(|o| &o.hash)))
+			.select((variants::object, (variants::hash, variants::codec, objects::mime, objects::size)))
+			.load(&mut conn)?;
+
+		let mut grouped: HashMap<String, Vec<Variant>> = HashMap::new();
+		for (object, variant) in variants {
+			grouped.entry(object).or_default().push(variant);
+		}
+
+		let page = objects.into_iter().map(|object| {
+			let variants = grouped.remove(&object.hash).unwrap_or_default();
+			ObjectVO { object, variants }
+		});
+		return Ok(page.collect());
+	}
+
+	pub fn receive_options<'a>(&'a self, headers: &'a HeaderMap) -> Result<ReceiveOptions<'a>, ReceiveError> {
+		return Ok(ReceiveOptions {
+			claimed_type: headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()),
+			allowed_types: &self.config.allowed_types,
+			hash: &self.config.hash,
+			digests: expected_digests(headers).map_err(ReceiveError::Digest)?,
+			max_size: self.config.max_size,
+			md5: false,
+		});
+	}
+
+	pub fn find(&self, hash: &str) -> DbResult<Option<Object>> {
+		let mut conn = self.ctx.db.get()?;
+		let object = objects::table
+			.find((&self.config.name, hash))
+			.select(Object::as_select())
+			.first(&mut conn)
+			.optional()?;
+		return Ok(object);
+	}
+
+	/// 删除对象及其记录和文件，返回 false 表示对象不存在。
+	///
+	/// 它的变体如果不再被其它对象或 S3 Key 引用也一起删除，否则只解除关联。
+	/// 指向该对象本身的 S3 Key 由外键级联删除，也就是说删除的是内容，而不只是这个 URL。
+	/// 记录的删除提交后才删除文件，见 unlink_unused。
+	fn remove(&self, hash: &str) -> DbResult<bool> {
+		let mut conn = self.ctx.db.get()?;
+		let bucket = &self.config.name;
+
+		let removed = conn.immediate_transaction(|conn| {
+			let variant_hashes: Vec<String> = variants::table
+				.filter(variants::bucket.eq(bucket))
+				.filter(variants::object.eq(hash))
+				.select(variants::hash)
+				.load(conn)?;
+
+			// 变体记录由外键级联删除。
+			let count = diesel::delete(objects::table.find((bucket, hash))).execute(conn)?;
+			if count == 0 {
+				return DbResult::Ok(Vec::new());
+			}
+
+			let mut removed = vec![hash.to_owned()];
+			for variant in variant_hashes {
+				let shared = variants::table
+					.filter(variants::bucket.eq(bucket))
+					.filter(variants::hash.eq(&variant));
+				let keys = s3_keys::table
+					.filter(s3_keys::bucket.eq(bucket))
+					.filter(s3_keys::hash.eq(&variant));
+				if diesel::select(diesel::dsl::exists(shared).or(diesel::dsl::exists(keys))).get_result(conn)? {
+					continue;
+				}
+				diesel::delete(objects::table.find((bucket, &variant))).execute(conn)?;
+				removed.push(variant);
+			}
+			DbResult::Ok(removed)
+		})?;
+
+		if removed.is_empty() {
+			return Ok(false);
+		}
+		self.unlink_unused(&mut conn, &removed)?;
+		log::debug!("Object {} removed from {}, records: {:?}", hash, bucket, removed);
+		return Ok(true);
+	}
+
+	/// 删除没有记录引用的文件，多个存储桶可能共用存储目录，所以检查其中所有存储桶的记录。
+	///
+	/// 检查和删除在同一个写事务中，以免与同时上传相同内容的请求交错，导致记录存在但文件已被删除。
+	/// 正在下载的请求已经打开了文件，删除后仍能读完（Windows 上删除会失败）。
+	fn unlink_unused(&self, conn: &mut SqliteConnection, hashes: &[String]) -> DbResult<()> {
+		return conn.immediate_transaction(|conn| {
+			for hash in hashes {
+				let used = objects::table
+					.filter(objects::bucket.eq_any(self.storage_buckets.iter()))
+					.filter(objects::hash.eq(hash));
+				if diesel::select(diesel::dsl::exists(used)).get_result(conn)? {
+					continue;
+				}
+				match std::fs::remove_file(self.ctx.data_dir.join(hash)) {
+					Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+					_ => {}
+				}
+			}
+			DbResult::Ok(())
+		});
+	}
+
+	/// 按服务端的偏好顺序，找出第一个客户端支持且存在的变体。
+	fn select_codec<'a>(&self, declared: &str, variants: &'a [Variant]) -> Option<&'a Variant> {
+		let declared: Vec<&str> = declared.split(',').map(str::trim).collect();
+
+		return self.config.codecs.iter()
+			.filter(|codec| declared.iter().any(|d| d.eq_ignore_ascii_case(codec)))
+			.find_map(|codec| variants.iter().find(|v| v.codec.as_ref() == Some(codec)));
+	}
+}
+
+/// 在格式变体中选出 Accept 权重最高的，权重相同时选体积小的。
+/// 没有匹配时返回 None，由原始文件兜底。
+fn select_format<'a>(headers: &HeaderMap, variants: &'a [Variant]) -> Option<&'a Variant> {
+	let mut formats: Vec<_> = variants.iter().filter(|v| v.codec.is_none()).collect();
+	formats.sort_by_key(|v| v.size);
+
+	let mimes: Vec<_> = formats.iter().map(|v| v.mime.as_str()).collect();
+	let best = *preferred_types(headers, &mimes).first()?;
+	return formats.into_iter().find(|v| v.mime == best);
+}
+
+/// Hash 只包含 URL-Safe base64 的字符，检查它可以防止 `..` 之类的路径。
+fn is_hash(value: &str) -> bool {
+	!value.is_empty() && value.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+}
+
+/// 从上传请求的头部读取对象的元数据，文件名来自 Content-Disposition，
+/// 非 ASCII 的文件名需要用 `filename*=UTF-8''...` 的形式编码。
+///
+/// https://www.rfc-editor.org/rfc/rfc6266#section-4.3
+fn upload_meta(bucket: &str, headers: &HeaderMap, identity: Option<Extension<Identity>>) -> UploadMeta {
+	let disposition = headers.get(CONTENT_DISPOSITION).and_then(|v| v.to_str().ok());
+	let filename = disposition.and_then(|value| {
+		let params = value.split(';').map(str::trim);
+		let mut plain = None;
+
+		for param in params {
+			if let Some(encoded) = param.strip_prefix("filename*=") {
+				let (charset, rest) = encoded.split_once('\'')?;
+				let (_, encoded) = rest.split_once('\'')?;
+				if charset.eq_ignore_ascii_case("UTF-8") {
+					return percent_decode_str(encoded).decode_utf8().ok().map(|s| s.into_owned());
+				}
+			} else if let Some(name) = param.strip_prefix("filename=") {
+				plain = Some(name.trim_matches('"').to_owned());
+			}
+		}
+		plain
+	});
+
+	let uploader = identity.map(|Extension(Identity(name))| name);
+	return UploadMeta { bucket: bucket.to_owned(), filename, uploader };
+}
+
+/// 根据 Content-Length 尽早拒绝过大的上传，大小未知时（chunked）由 `ReceiveOptions::max_size` 在接收中检查。
+pub fn check_length(config: &BucketConfig, headers: &HeaderMap) -> Result<(), StatusCode> {
+	let length = headers.get(CONTENT_LENGTH)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.parse::<u64>().ok());
+
+	match (config.max_size, length) {
+		(Some(max), Some(length)) if length > max => Err(StatusCode::PAYLOAD_TOO_LARGE),
+		_ => Ok(()),
+	}
+}
+
+pub fn save_error(e: Box<dyn Error + Send + Sync>) -> Response {
+	if e.is::<HashCollision>() {
+		log::error!("{}", e);
+		return StatusCode::CONFLICT.into_response();
+	}
+	log::error!("Failed to save upload: {}", e);
+	return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+}
+
+async fn upload(
+	state: State<ManualBucket>,
+	identity: Option<Extension<Identity>>,
+	headers: HeaderMap,
+	body: BodyStream,
+) -> Response {
+	if let Err(status) = check_length(&state.config, &headers) {
+		return status.into_response();
+	}
+	let options = match state.receive_options(&headers) {
+		Ok(options) => options,
+		Err(e) => return e.into_response(),
+	};
+	let buf = match state.ctx.receive_file(body, options).await {
+		Ok(buf) => buf,
+		Err(e) => return e.into_response(),
+	};
+	let meta = upload_meta(&state.config.name, &headers, identity);
+	return match buf.save(meta, state.config.verify_duplicates).await {
+		Ok((object, existed)) => Json(UploadVO { hash: object.hash, existed }).into_response(),
+		Err(e) => save_error(e),
+	};
+}
+
+#[derive(Deserialize)]
+struct VariantQuery {
+	codec: Option<String>,
+}
+
+/// 给已有的对象上传一个变体，类型由 Content-Type 指定，视频等还可以用 `codec` 参数指定编码。
+/// 同一编码（没有编码时则是同一类型）的旧变体会被替换，但文件和对象记录不会删除。
+async fn upload_variant(
+	state: State<ManualBucket>,
+	Path(hash): Path<String>,
+	Query(query): Query<VariantQuery>,
+	identity: Option<Extension<Identity>>,
+	headers: HeaderMap,
+	body: BodyStream,
+) -> Response {
+	if !is_hash(&hash) {
+		return StatusCode::NOT_FOUND.into_response();
+	}
+	match state.find(&hash) {
+		Ok(Some(_)) => {}
+		Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+		Err(e) => return save_error(e),
+	}
+
+	if let Err(status) = check_length(&state.config, &headers) {
+		return status.into_response();
+	}
+	let codec = query.codec.filter(|c| !c.is_empty());
+
+	let options = match state.receive_options(&headers) {
+		Ok(options) => options,
+		Err(e) => return e.into_response(),
+	};
+	let buf = match state.ctx.receive_file(body, options).await {
+		Ok(buf) => buf,
+		Err(e) => return e.into_response(),
+	};
+	let meta = upload_meta(&state.config.name, &headers, identity);
+	let (variant, existed) = match buf.save(meta, state.config.verify_duplicates).await {
+		Ok(saved) => saved,
+		Err(e) => return save_error(e),
+	};
+	if let Err(e) = state.add_variant(&hash, &variant, codec) {
+		// 新保存的文件不属于任何对象，删除它以免成为孤儿，已存在的则可能被别处引用。
+		if !existed {
+			if let Err(e) = state.remove(&variant.hash) {
+				log::error!("Failed to remove orphaned variant {}: {}", variant.hash, e);
+			}
+		}
+		return save_error(e);
+	}
+
+	log::trace!("New variant {} added to {}", variant.hash, hash);
+	return Json(UploadVO { hash: variant.hash, existed }).into_response();
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+	cursor: Option<String>,
+	limit: Option<i64>,
+
+	/// MIME 类型，支持 `image/*` 这样的通配。
+	r#type: Option<String>,
+
+	/// Unix 时间戳，只列出此后创建的对象。
+	since: Option<i64>,
+}
+
+impl ListQuery {
+	fn limit(&self) -> i64 {
+		return self.limit.unwrap_or(100).clamp(1, 1000);
+	}
+}
+
+#[derive(Serialize)]
+struct ObjectVO {
+	#[serde(flatten)]
+	object: Object,
+	variants: Vec<Variant>,
+}
+
+/// 游标是上一页最后一个对象的 `<created_at>.<hash>`，Hash 中不会有点号。
+#[derive(Serialize)]
+struct ListVO {
+	items: Vec<ObjectVO>,
+	cursor: Option<String>,
+}
+
+async fn list(state: State<ManualBucket>, Query(query): Query<ListQuery>) -> Response {
+	let cursor = match query.cursor.as_deref().filter(|c| !c.is_empty()) {
+		None => None,
+		Some(cursor) => match cursor.split_once('.') {
+			Some((time, hash)) if is_hash(hash) => match time.parse::<i64>() {
+				Ok(time) => Some((time, hash)),
+				Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+			},
+			_ => return StatusCode::BAD_REQUEST.into_response(),
+		},
+	};
+
+	let items = match state.list(&query, cursor) {
+		Ok(items) => items,
+		Err(e) => {
+			log::error!("Failed to list objects: {}", e);
+			return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+		}
+	};
+
+	// 不满一页说明已经到底了，省掉客户端多请求一次空页。
+	let cursor = match items.last() {
+		Some(last) if items.len() as i64 == query.limit() => {
+			Some(format!("{}.{}", last.object.created_at, last.object.hash))
+		}
+		_ => None,
+	};
+	return Json(ListVO { items, cursor }).into_response();
+}
 
-	buf.save().unwrap();
-	return Json(UploadVO { hash }).into_response();
+async fn remove(
+	state: State<ManualBucket>,
+	Path(hash): Path<String>,
+	identity: Option<Extension<Identity>>,
+) -> Response {
+	if !is_hash(&hash) {
+		return StatusCode::NOT_FOUND.into_response();
+	}
+	return match state.remove(&hash) {
+		Ok(true) => {
+			let who = identity.map(|Extension(Identity(name))| name);
+			log::info!("{} removed {}/{}", who.as_deref().unwrap_or("Anonymous"), state.config.name, hash);
+			StatusCode::NO_CONTENT.into_response()
+		}
+		Ok(false) => StatusCode::NOT_FOUND.into_response(),
+		Err(e) => {
+			log::error!("Failed to remove {}: {}", hash, e);
+			StatusCode::INTERNAL_SERVER_ERROR.into_response()
+		}
+	};
 }
 
 const IMMUTABLE: &str = "public,max-age=31536000,immutable";
 
-async fn download(state: State<ManualBucket>, Path(hash): Path<String>, headers: HeaderMap) -> Response {
-	let path = state.ctx.data_dir.join(&hash);
-	let file = FileRangeReadr::open(path, "image/png".into(), FileCache::Hashed(hash));
-	match file.await {
-		Ok(file) => {
+/// 私有存储桶的对象不能被共享缓存保存，否则签名过期后仍能从 CDN 访问。
+const PRIVATE_IMMUTABLE: &str = "private,max-age=31536000,immutable";
+
+/// 可以缓存，但每次使用前都要用 ETag 验证，变体改变时能及时拿到新的版本。
+const NEGOTIATED: &str = "public,no-cache";
+const PRIVATE_NEGOTIATED: &str = "private,no-cache";
+
+async fn download(
+	state: State<ManualBucket>,
+	Path(hash): Path<String>,
+	Query(params): Query<HashMap<String, String>>,
+	headers: HeaderMap,
+) -> Response {
+	if !is_hash(&hash) {
+		return StatusCode::NOT_FOUND.into_response();
+	}
+
+	let object = match state.find(&hash) {
+		Ok(Some(object)) => object,
+		Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+		Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+	};
+
+	let variants = match state.load_variants(&hash) {
+		Ok(variants) => variants,
+		Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+	};
+
+	let declared = match &state.config.codec_detect {
+		None => None,
+		Some(CodecDetect::Param(name)) => params.get(name).map(String::as_str),
+		Some(CodecDetect::Header(name)) => headers.get(name).and_then(|v| v.to_str().ok()),
+	};
+
+	let selected = declared
+		.and_then(|declared| state.select_codec(declared, &variants))
+		.or_else(|| select_format(&headers, &variants));
+
+	// 没有变体的对象直接返回文件本身，只有需要选择的响应才每次验证。
+	let cache_control = if variants.is_empty() {
+		&state.cache_control
+	} else {
+		&state.negotiated_cache_control
+	};
+
+	// 每个变体都有自己的 Hash，作为 ETag 就不会让缓存混淆不同的版本。
+	let (target, mime) = match selected {
+		Some(variant) => (variant.hash.clone(), variant.mime.clone()),
+		None => (object.hash, object.mime),
+	};
+
+	// 允许列表之外的类型（比如修改配置前上传的）不能原样返回，否则浏览器可能执行其中的脚本。
+	let allowed = is_allowed_type(&state.config.allowed_types, &mime);
+	let mime = if allowed { mime } else { OCTET_STREAM.to_owned() };
+
+	let path = state.ctx.data_dir.join(&target);
+	let file = FileRangeReadr::open(path, mime, FileCache::Hashed(target));
+	let mut response = match file.await {
+		Ok(mut file) => {
+			file.compress = Some(state.ctx.compress.clone());
 			let mut response = send_range(&headers, file).await;
-			response.headers_mut().append(CACHE_CONTROL, HeaderValue::from_static(IMMUTABLE));
+			response.headers_mut().append(CACHE_CONTROL, cache_control.clone());
+			if !allowed {
+				response.headers_mut().insert(CONTENT_DISPOSITION, HeaderValue::from_static("attachment"));
+			}
+			response.headers_mut().insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
 			response
 		}
 		Err(e) => match e.kind() {
 			ErrorKind::NotFound => StatusCode::NOT_FOUND.into_response(),
 			_ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
 		}
+	};
+
+	// 错误响应与选择无关，只有成功的响应（包括 304）需要告诉缓存按哪些头区分。
+	let status = response.status();
+	if !status.is_success() && status != StatusCode::NOT_MODIFIED {
+		return response;
+	}
+	if let Some(CodecDetect::Header(name)) = &state.config.codec_detect {
+		response.headers_mut().append(VARY, HeaderValue::from_str(name.as_str()).unwrap());
+	}
+	if variants.iter().any(|v| v.codec.is_none()) {
+		response.headers_mut().append(VARY, HeaderValue::from_static("Accept"));
+	}
+	return response;
+}
+
+/// 测试用的存储桶，存储目录必须已经在配置中指定。
+#[cfg(test)]
+pub fn test_bucket(mut ctx: OSSContext, config: BucketConfig) -> ManualBucket {
+	ctx.data_dir = config.storage.clone().unwrap();
+	std::fs::create_dir_all(&ctx.data_dir).unwrap();
+	return ManualBucket::new(ctx, config);
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use axum::body::Body;
+	use axum::http::{HeaderMap, Method, Request, StatusCode};
+	use axum::http::header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, ETAG, IF_NONE_MATCH, RANGE, VARY};
+	use axum::response::Response;
+	use axum::Router;
+	use axum_extra::extract::CookieJar;
+	use diesel::prelude::*;
+	use tempfile::TempDir;
+	use tower::ServiceExt;
+
+	use crate::context::{OSSContext, test_context};
+	use crate::db::{self, import_files};
+	use crate::manual::{BucketConfig, CodecDetect, manual_bucket, ManualBucket, test_bucket};
+	use crate::schema::{objects, s3_keys};
+	use crate::session::{create_session, SESSION_COOKIE};
+	use crate::user::bootstrap_admin;
+
+	const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+	/// 允许匿名访问的存储桶，用于测试登录以外的功能。
+	struct TestBucket {
+		dir: TempDir,
+		app: Router,
+	}
+
+	fn public_bucket(configure: impl FnOnce(&mut BucketConfig)) -> TestBucket {
+		let dir = tempfile::tempdir().unwrap();
+		let mut ctx = test_context(dir.path());
+		ctx.allow_anonymous = true;
+		let mut config = BucketConfig::default_image(dir.path());
+		configure(&mut config);
+
+		let app = manual_bucket(test_bucket(ctx, config));
+		return TestBucket { dir, app };
+	}
+
+	/// 内容不同的 PNG 文件，能被识别为 image/png。
+	fn png(tag: u8) -> Vec<u8> {
+		return [PNG, &[tag]].concat();
+	}
+
+	impl TestBucket {
+		async fn call(&self, request: Request<Body>) -> Response {
+			return self.app.clone().oneshot(request).await.unwrap();
+		}
+
+		/// 上传文件并返回 Hash 和 existed，uri 是 `/` 或者 `/<hash>?codec=...` 上传变体。
+		async fn upload(&self, uri: &str, mime: &str, data: Vec<u8>) -> (String, bool) {
+			let request = Request::post(uri).header(CONTENT_TYPE, mime).body(Body::from(data)).unwrap();
+			let response = self.call(request).await;
+			assert_eq!(response.status(), StatusCode::OK);
+
+			let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+			let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+			return (json["hash"].as_str().unwrap().to_owned(), json["existed"].as_bool().unwrap());
+		}
+
+		async fn get(&self, uri: &str, headers: &[(&str, &str)]) -> Response {
+			let mut request = Request::get(uri);
+			for (name, value) in headers {
+				request = request.header(*name, *value);
+			}
+			return self.call(request.body(Body::empty()).unwrap()).await;
+		}
+	}
+
+	fn etag(response: &Response) -> &str {
+		return response.headers()[ETAG].to_str().unwrap().trim_matches('"');
+	}
+
+	fn vary(headers: &HeaderMap) -> Vec<&str> {
+		return headers.get_all(VARY).iter().map(|v| v.to_str().unwrap()).collect();
+	}
+
+	/// 使用默认访问策略的图片桶：下载公开，上传和删除需要登录。
+	fn image_bucket(dir: &std::path::Path, ctx: OSSContext) -> Router {
+		return manual_bucket(test_bucket(ctx, BucketConfig::default_image(dir)));
+	}
+
+	async fn send(app: &Router, method: Method, uri: &str, cookie: Option<&str>) -> StatusCode {
+		let mut request = Request::builder().method(method).uri(uri).header(CONTENT_TYPE, "image/png");
+		if let Some(cookie) = cookie {
+			request = request.header(COOKIE, cookie);
+		}
+		let request = request.body(Body::from(PNG)).unwrap();
+		return app.clone().oneshot(request).await.unwrap().status();
+	}
+
+	#[tokio::test]
+	async fn anonymous_requests() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = test_context(dir.path());
+		bootstrap_admin(&ctx.db, "pw").unwrap();
+		let jar = create_session(&ctx, CookieJar::new(), "admin", None).unwrap();
+		let cookie = jar.get(SESSION_COOKIE).unwrap().stripped().to_string();
+		let app = image_bucket(dir.path(), ctx);
+
+		assert_eq!(send(&app, Method::POST, "/", None).await, StatusCode::UNAUTHORIZED);
+		assert_eq!(send(&app, Method::POST, "/", Some(&cookie)).await, StatusCode::OK);
+
+		let hash = std::fs::read_dir(dir.path().join("files")).unwrap().next().unwrap().unwrap().file_name();
+		let uri = format!("/{}", hash.to_str().unwrap());
+		assert_eq!(send(&app, Method::GET, &uri, None).await, StatusCode::OK);
+		assert_eq!(send(&app, Method::DELETE, &uri, None).await, StatusCode::UNAUTHORIZED);
+		assert_eq!(send(&app, Method::DELETE, &uri, Some(&cookie)).await, StatusCode::NO_CONTENT);
+	}
+
+	#[tokio::test]
+	async fn no_users() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut ctx = test_context(dir.path());
+		let app = image_bucket(dir.path(), ctx.clone());
+		assert_eq!(send(&app, Method::POST, "/", None).await, StatusCode::SERVICE_UNAVAILABLE);
+		assert_eq!(send(&app, Method::GET, "/abc", None).await, StatusCode::NOT_FOUND);
+
+		ctx.allow_anonymous = true;
+		let app = image_bucket(dir.path(), ctx.clone());
+		assert_eq!(send(&app, Method::POST, "/", None).await, StatusCode::OK);
+
+		// 创建了用户后立即需要登录，不用重启。
+		bootstrap_admin(&ctx.db, "pw").unwrap();
+		assert_eq!(send(&app, Method::POST, "/", None).await, StatusCode::UNAUTHORIZED);
+	}
+
+	#[tokio::test]
+	async fn too_large() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = test_context(dir.path());
+		let buf_dir = ctx.buf_dir.clone();
+		let config = BucketConfig { max_size: Some(10), ..BucketConfig::default_image(dir.path()) };
+		let mut state = test_bucket(ctx, config);
+		state.ctx.allow_anonymous = true;
+		let app: Router = manual_bucket(state);
+
+		// 声明的长度超出时不接收请求体。
+		let request = Request::post("/")
+			.header(CONTENT_TYPE, "image/png")
+			.header(CONTENT_LENGTH, 100)
+			.body(Body::from(vec![0; 100]))
+			.unwrap();
+		assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+		// 没有声明长度时在接收中途停止。
+		let chunks = vec![Ok::<_, std::io::Error>(PNG.to_vec()), Ok(PNG.to_vec())];
+		let request = Request::post("/")
+			.header(CONTENT_TYPE, "image/png")
+			.body(Body::wrap_stream(futures::stream::iter(chunks)))
+			.unwrap();
+		assert_eq!(app.oneshot(request).await.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+		assert_eq!(std::fs::read_dir(buf_dir).unwrap().count(), 0);
+	}
+
+	/// 上传原始文件和 av1、hevc 两个编码的变体，返回它们的 Hash。
+	async fn codec_variants(bucket: &TestBucket) -> (String, String, String) {
+		let (object, _) = bucket.upload("/", "image/png", png(0)).await;
+		let (av1, _) = bucket.upload(&format!("/{}?codec=av1", object), "image/png", png(1)).await;
+		let (hevc, _) = bucket.upload(&format!("/{}?codec=hevc", object), "image/png", png(2)).await;
+		return (object, av1, hevc);
+	}
+
+	#[tokio::test]
+	async fn codec_param() {
+		let bucket = public_bucket(|config| {
+			config.codec_detect = Some(CodecDetect::Param("codecs".into()));
+			config.codecs = vec!["av1".into(), "hevc".into()];
+		});
+		let (object, av1, hevc) = codec_variants(&bucket).await;
+
+		// 按服务端的偏好顺序选择，与客户端列出的顺序无关。
+		let response = bucket.get(&format!("/{}?codecs=hevc,av1", object), &[]).await;
+		assert_eq!(etag(&response), av1);
+		let response = bucket.get(&format!("/{}?codecs=HEVC", object), &[]).await;
+		assert_eq!(etag(&response), hevc);
+
+		// 不支持任何编码或没有声明时返回原始文件，查询参数是 URL 的一部分，不需要 Vary。
+		let response = bucket.get(&format!("/{}?codecs=vp9", object), &[]).await;
+		assert_eq!(etag(&response), object);
+		let response = bucket.get(&format!("/{}", object), &[]).await;
+		assert_eq!(etag(&response), object);
+		assert!(vary(response.headers()).iter().all(|v| !v.contains("codecs")));
+	}
+
+	#[tokio::test]
+	async fn codec_header() {
+		let bucket = public_bucket(|config| {
+			config.codec_detect = Some(CodecDetect::Header("x-codecs".try_into().unwrap()));
+			config.codecs = vec!["hevc".into(), "av1".into()];
+		});
+		let (object, _, hevc) = codec_variants(&bucket).await;
+		let uri = format!("/{}", object);
+
+		let response = bucket.get(&uri, &[("x-codecs", "av1, hevc")]).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(etag(&response), hevc);
+		assert!(vary(response.headers()).contains(&"x-codecs"));
+
+		let tag = format!("\"{}\"", hevc);
+		let response = bucket.get(&uri, &[("x-codecs", "hevc"), (IF_NONE_MATCH.as_str(), &tag)]).await;
+		assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+		assert!(vary(response.headers()).contains(&"x-codecs"));
+
+		// 错误响应与选择无关，不带 Vary。
+		let response = bucket.get(&uri, &[("x-codecs", "hevc"), (RANGE.as_str(), "bytes=1000-")]).await;
+		assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+		assert!(!vary(response.headers()).contains(&"x-codecs"));
+		let response = bucket.get("/unknown", &[("x-codecs", "hevc")]).await;
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+		assert!(vary(response.headers()).is_empty());
+	}
+
+	const IMMUTABLE: &str = "public,max-age=31536000,immutable";
+
+	/// 上传 PNG 原始文件和 WebP、AVIF 两个格式的变体，返回它们的 Hash。
+	async fn format_variants(bucket: &TestBucket) -> (String, String, String) {
+		let (object, _) = bucket.upload("/", "image/png", png(0)).await;
+		let uri = format!("/{}", object);
+		let (webp, _) = bucket.upload(&uri, "image/webp", b"RIFF\0\0\0\0WEBPVP8 ".to_vec()).await;
+		let (avif, _) = bucket.upload(&uri, "image/avif", b"\0\0\0\x10ftypavif\0\0\0\0".to_vec()).await;
+		return (object, webp, avif);
+	}
+
+	#[tokio::test]
+	async fn format_accept() {
+		let bucket = public_bucket(|_| {});
+		let (object, webp, avif) = format_variants(&bucket).await;
+		let uri = format!("/{}", object);
+
+		// 每个变体的 ETag 都是自己的 Hash。
+		let response = bucket.get(&uri, &[("accept", "image/avif,image/webp;q=0.9,*/*;q=0.8")]).await;
+		assert_eq!(response.headers()[CONTENT_TYPE], "image/avif");
+		assert_eq!(etag(&response), avif);
+		assert_eq!(vary(response.headers()), ["Accept"]);
+
+		let response = bucket.get(&uri, &[("accept", "image/webp,*/*")]).await;
+		assert_eq!(response.headers()[CONTENT_TYPE], "image/webp");
+		assert_eq!(etag(&response), webp);
+
+		// 通配符不算，没有匹配时返回原始文件，仍然需要 Vary。
+		let response = bucket.get(&uri, &[("accept", "image/*")]).await;
+		assert_eq!(etag(&response), object);
+		assert_eq!(vary(response.headers()), ["Accept"]);
+		let response = bucket.get(&uri, &[]).await;
+		assert_eq!(etag(&response), object);
+
+		let tag = format!("\"{}\"", webp);
+		let response = bucket.get(&uri, &[("accept", "image/webp"), (IF_NONE_MATCH.as_str(), &tag)]).await;
+		assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+		assert_eq!(vary(response.headers()), ["Accept"]);
+	}
+
+	#[tokio::test]
+	async fn cache_control() {
+		let bucket = public_bucket(|_| {});
+		let (plain, _) = bucket.upload("/", "image/png", png(9)).await;
+		let (object, webp, _) = format_variants(&bucket).await;
+
+		// 没有变体的对象和变体自己都可以永久缓存，需要选择的对象每次验证。
+		let response = bucket.get(&format!("/{}", plain), &[]).await;
+		assert_eq!(response.headers()[CACHE_CONTROL], IMMUTABLE);
+		assert!(vary(response.headers()).is_empty());
+		let response = bucket.get(&format!("/{}", webp), &[("accept", "image/webp")]).await;
+		assert_eq!(response.headers()[CACHE_CONTROL], IMMUTABLE);
+		assert!(vary(response.headers()).is_empty());
+		let response = bucket.get(&format!("/{}", object), &[("accept", "image/webp")]).await;
+		assert_eq!(response.headers()[CACHE_CONTROL], "public,no-cache");
+
+		// 配置的值用于所有响应。
+		let bucket = public_bucket(|config| config.cache_control = Some("no-store".into()));
+		let (plain, _) = bucket.upload("/", "image/png", png(9)).await;
+		let (object, _, _) = format_variants(&bucket).await;
+		for hash in [plain, object] {
+			let response = bucket.get(&format!("/{}", hash), &[]).await;
+			assert_eq!(response.headers()[CACHE_CONTROL], "no-store");
+		}
+	}
+
+	#[tokio::test]
+	async fn import_legacy_files() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut ctx = test_context(dir.path());
+		ctx.allow_anonymous = true;
+		let storage = dir.path().join("files");
+		std::fs::create_dir_all(&storage).unwrap();
+		std::fs::write(storage.join("legacyPng"), png(0)).unwrap();
+		std::fs::write(storage.join("legacyText"), b"plain text").unwrap();
+
+		assert_eq!(import_files(&ctx.db, "image", &storage).unwrap(), 2);
+		let app = image_bucket(dir.path(), ctx);
+
+		// 旧文件没有扩展名，类型来自文件头。
+		let response = app.clone().oneshot(Request::get("/legacyPng").body(Body::empty()).unwrap()).await.unwrap();
+		assert_eq!(response.headers()[CONTENT_TYPE], "image/png");
+		assert!(response.headers().get(CONTENT_DISPOSITION).is_none());
+
+		let response = app.oneshot(Request::get("/legacyText").body(Body::empty()).unwrap()).await.unwrap();
+		assert_eq!(response.headers()[CONTENT_TYPE], "application/octet-stream");
+		assert_eq!(response.headers()[CONTENT_DISPOSITION], "attachment");
+	}
+
+	#[tokio::test]
+	async fn content_type() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut ctx = test_context(dir.path());
+		ctx.allow_anonymous = true;
+		let config = BucketConfig { allowed_types: vec![], ..BucketConfig::default_image(dir.path()) };
+		let bucket = TestBucket {
+			app: manual_bucket(test_bucket(ctx.clone(), config)),
+			dir,
+		};
+
+		// 能识别的类型以文件头为准，不能识别的使用请求中的类型。
+		let (png, _) = bucket.upload("/", "application/octet-stream", png(0)).await;
+		let (json, _) = bucket.upload("/", "application/json; charset=utf-8", b"{}".to_vec()).await;
+
+		let response = bucket.get(&format!("/{}", png), &[]).await;
+		assert_eq!(response.headers()[CONTENT_TYPE], "image/png");
+		let response = bucket.get(&format!("/{}", json), &[]).await;
+		assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+		assert!(response.headers().get(CONTENT_DISPOSITION).is_none());
+
+		// 修改配置后，允许列表之外的旧对象作为附件下载。
+		let app = image_bucket(bucket.dir.path(), ctx);
+		let response = app.clone().oneshot(Request::get(format!("/{}", json)).body(Body::empty()).unwrap()).await.unwrap();
+		assert_eq!(response.headers()[CONTENT_TYPE], "application/octet-stream");
+		assert_eq!(response.headers()[CONTENT_DISPOSITION], "attachment");
+		assert_eq!(response.headers()["x-content-type-options"], "nosniff");
+		let response = app.oneshot(Request::get(format!("/{}", png)).body(Body::empty()).unwrap()).await.unwrap();
+		assert_eq!(response.headers()[CONTENT_TYPE], "image/png");
+	}
+
+	impl TestBucket {
+		async fn delete(&self, hash: &str) -> StatusCode {
+			let request = Request::delete(format!("/{}", hash)).body(Body::empty()).unwrap();
+			return self.call(request).await.status();
+		}
+
+		fn stored(&self, hash: &str) -> bool {
+			return self.dir.path().join("files").join(hash).exists();
+		}
+	}
+
+	#[tokio::test]
+	async fn remove() {
+		let bucket = public_bucket(|_| {});
+		let (object, webp, avif) = format_variants(&bucket).await;
+
+		// 另一个对象也使用同一个 WebP 变体。
+		let (other, _) = bucket.upload("/", "image/png", png(1)).await;
+		let (shared, existed) = bucket.upload(&format!("/{}", other), "image/webp", b"RIFF\0\0\0\0WEBPVP8 ".to_vec()).await;
+		assert_eq!((shared.as_str(), existed), (webp.as_str(), true));
+
+		assert_eq!(bucket.delete(&object).await, StatusCode::NO_CONTENT);
+		assert_eq!(bucket.delete(&object).await, StatusCode::NOT_FOUND);
+		assert_eq!(bucket.get(&format!("/{}", object), &[]).await.status(), StatusCode::NOT_FOUND);
+		assert!(!bucket.stored(&object));
+
+		// 只属于它的变体一起删除，共用的保留。
+		assert_eq!(bucket.get(&format!("/{}", avif), &[]).await.status(), StatusCode::NOT_FOUND);
+		assert!(!bucket.stored(&avif));
+		let response = bucket.get(&format!("/{}", other), &[("accept", "image/webp")]).await;
+		assert_eq!(etag(&response), webp);
+		assert!(bucket.stored(&webp));
+
+		assert_eq!(bucket.delete(&other).await, StatusCode::NO_CONTENT);
+		assert_eq!(bucket.get(&format!("/{}", webp), &[]).await.status(), StatusCode::NOT_FOUND);
+		assert_eq!(std::fs::read_dir(bucket.dir.path().join("files")).unwrap().count(), 0);
+	}
+
+	#[tokio::test]
+	async fn remove_s3_keys() {
+		let bucket = public_bucket(|_| {});
+		let db = db::open(&bucket.dir.path().join("index.db")).unwrap();
+		let (object, webp, _) = format_variants(&bucket).await;
+
+		let insert = |key: &str, hash: &str| {
+			diesel::insert_into(s3_keys::table)
+				.values((
+					s3_keys::bucket.eq("image"),
+					s3_keys::key.eq(key),
+					s3_keys::hash.eq(hash),
+					s3_keys::created_at.eq(0),
+				))
+				.execute(&mut db.get().unwrap())
+				.unwrap();
+		};
+		insert("object.png", &object);
+		insert("variant.webp", &webp);
+
+		// 指向对象的 Key 级联删除，被 Key 引用的变体保留。
+		assert_eq!(bucket.delete(&object).await, StatusCode::NO_CONTENT);
+		let keys: Vec<String> = s3_keys::table.select(s3_keys::key).load(&mut db.get().unwrap()).unwrap();
+		assert_eq!(keys, ["variant.webp"]);
+		assert_eq!(bucket.get(&format!("/{}", webp), &[]).await.status(), StatusCode::OK);
+		assert!(bucket.stored(&webp));
+	}
+
+	#[tokio::test]
+	async fn remove_storage() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut ctx = test_context(dir.path());
+		ctx.allow_anonymous = true;
+		let config = |name: &str, storage: &str| BucketConfig {
+			name: name.into(),
+			storage: Some(dir.path().join(storage)),
+			..BucketConfig::default_image(dir.path())
+		};
+		let call = |state: &ManualBucket, request: Request<Body>| {
+			let app: Router = manual_bucket(state.clone());
+			async move { app.oneshot(request).await.unwrap().status() }
+		};
+		let upload = || Request::post("/").header(CONTENT_TYPE, "image/png").body(Body::from(PNG)).unwrap();
+
+		// 不同目录中的相同文件各自删除。
+		let a = test_bucket(ctx.clone(), config("a", "a"));
+		let b = test_bucket(ctx.clone(), config("b", "b"));
+		assert_eq!(call(&a, upload()).await, StatusCode::OK);
+		assert_eq!(call(&b, upload()).await, StatusCode::OK);
+		let hash = std::fs::read_dir(dir.path().join("a")).unwrap().next().unwrap().unwrap().file_name();
+		let hash = hash.to_str().unwrap();
+		let delete = || Request::delete(format!("/{}", hash)).body(Body::empty()).unwrap();
+
+		assert_eq!(call(&a, delete()).await, StatusCode::NO_CONTENT);
+		assert!(!dir.path().join("a").join(hash).exists());
+		assert!(dir.path().join("b").join(hash).exists());
+
+		// 共用目录时，直到没有桶引用才删除文件。
+		let shared: Arc<[String]> = Arc::new(["c".into(), "d".into()]);
+		let mut c = test_bucket(ctx.clone(), config("c", "shared"));
+		let mut d = test_bucket(ctx, config("d", "shared"));
+		c.storage_buckets = shared.clone();
+		d.storage_buckets = shared;
+		assert_eq!(call(&c, upload()).await, StatusCode::OK);
+		assert_eq!(call(&d, upload()).await, StatusCode::OK);
+
+		assert_eq!(call(&c, delete()).await, StatusCode::NO_CONTENT);
+		assert!(dir.path().join("shared").join(hash).exists());
+		assert_eq!(call(&d, delete()).await, StatusCode::NO_CONTENT);
+		assert!(!dir.path().join("shared").join(hash).exists());
+	}
+
+	impl TestBucket {
+		/// 列出对象，返回 Hash 列表和下一页的游标。
+		async fn list(&self, query: &str) -> (Vec<String>, Option<String>) {
+			let response = self.get(&format!("/?{}", query), &[]).await;
+			assert_eq!(response.status(), StatusCode::OK);
+
+			let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+			let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+			let hashes = json["items"].as_array().unwrap().iter()
+				.map(|item| item["hash"].as_str().unwrap().to_owned())
+				.collect();
+			return (hashes, json["cursor"].as_str().map(str::to_owned));
+		}
+	}
+
+	#[tokio::test]
+	async fn list() {
+		let bucket = public_bucket(|config| config.allowed_types = vec![]);
+		let db = db::open(&bucket.dir.path().join("index.db")).unwrap();
+
+		let mut images = Vec::new();
+		for tag in 0..5 {
+			images.push(bucket.upload("/", "image/png", png(tag)).await.0);
+		}
+		let (json, _) = bucket.upload("/", "application/json", b"{}".to_vec()).await;
+		bucket.upload(&format!("/{}", images[0]), "image/webp", b"RIFF\0\0\0\0WEBPVP8 ".to_vec()).await;
+
+		// 大多数对象的创建时间相同，翻页靠 Hash 区分。
+		let set_time = |hash: Option<&str>, time: i64| {
+			let mut conn = db.get().unwrap();
+			match hash {
+				Some(hash) => diesel::update(objects::table.filter(objects::hash.eq(hash)))
+					.set(objects::created_at.eq(time))
+					.execute(&mut conn),
+				None => diesel::update(objects::table).set(objects::created_at.eq(time)).execute(&mut conn),
+			}.unwrap();
+		};
+		set_time(None, 100);
+		set_time(Some(&images[4]), 200);
+		set_time(Some(&json), 50);
+
+		let mut expected = images[..4].to_vec();
+		expected.sort_unstable_by(|a, b| b.cmp(a));
+		expected.insert(0, images[4].clone());
+		expected.push(json.clone());
+
+		let mut pages = Vec::new();
+		let mut cursor = String::new();
+		loop {
+			let (page, next) = bucket.list(&format!("limit=2&cursor={}", cursor)).await;
+			pages.extend(page);
+			match next {
+				Some(next) => cursor = next,
+				None => break,
+			}
+		}
+		assert_eq!(pages, expected);
+
+		assert_eq!(bucket.list("type=image/*").await.0.len(), 5);
+		assert_eq!(bucket.list("type=application/json").await.0, [json]);
+		assert_eq!(bucket.list("type=*/*").await.0.len(), 6);
+
+		// LIKE 的通配符按字面匹配。
+		assert!(bucket.list("type=ima_e/*").await.0.is_empty());
+		assert!(bucket.list("type=%25/*").await.0.is_empty());
+
+		assert_eq!(bucket.list("since=100").await.0, expected[..5]);
+		assert_eq!(bucket.list("since=150").await.0[..], images[4..]);
+
+		assert_eq!(bucket.list("limit=0").await.0.len(), 1);
+		assert_eq!(bucket.list("limit=5000").await, (expected, None));
+
+		for cursor in ["abc", "x.abc", "100.a/b", "100."] {
+			let response = bucket.get(&format!("/?cursor={}", cursor), &[]).await;
+			assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", cursor);
+		}
 	}
 }
@@ -0,0 +1,147 @@
+use axum::http::HeaderMap;
+use axum::http::header::{ACCEPT, ACCEPT_ENCODING};
+
+/// 解析 Accept 系列头部的值，比如 `br;q=1.0, gzip;q=0.8, *;q=0`，
+/// 返回每一项的值和权重，权重缺省为 1，无效的权重会让该项被忽略。
+///
+/// https://tools.ietf.org/html/rfc7231#section-5.3.1
+pub fn parse_qvalues(value: &str) -> Vec<(&str, f32)> {
+	let mut items = Vec::new();
+
+	for item in value.split(',') {
+		let mut params = item.split(';').map(str::trim);
+
+		let name = match params.next() {
+			Some(name) if !name.is_empty() => name,
+			_ => continue,
+		};
+
+		let q = params
+			.find_map(|p| p.strip_prefix("q=").or_else(|| p.strip_prefix("Q=")))
+			.map(|q| q.parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q)))
+			.unwrap_or(Some(1.0));
+
+		if let Some(q) = q {
+			items.push((name, q));
+		}
+	}
+
+	return items;
+}
+
+/// 根据 Accept-Encoding 对服务端支持的编码排序，返回客户端能接受的编码，
+/// 权重高的在前，权重相同的保持 `available` 中的顺序。
+///
+/// 没有 Accept-Encoding 头时虽然按规范可以使用任何编码，但为了兼容性还是只发送原始内容，
+/// 返回的列表不包含 identity，调用方在没有合适的编码时应当回退到原始内容。
+pub fn preferred_encodings<'a>(headers: &HeaderMap, available: &[&'a str]) -> Vec<&'a str> {
+	let value = match headers.get(ACCEPT_ENCODING).and_then(|v| v.to_str().ok()) {
+		Some(value) => value,
+		None => return Vec::new(),
+	};
+
+	let accepts = parse_qvalues(value);
+	return rank(available, |coding| {
+		let exact = accepts.iter().find(|(name, _)| name.eq_ignore_ascii_case(coding));
+		let any = accepts.iter().find(|(name, _)| *name == "*");
+		exact.or(any).map(|(_, q)| *q).unwrap_or(0.0)
+	});
+}
+
+/// 根据 Accept 对候选的 MIME 类型排序，返回客户端能接受的类型，规则同 `preferred_encodings`。
+///
+/// 与规范不同的是，这里只认明确列出的类型，`image/*` 和 `*/*` 之类的通配符被忽略。
+/// 因为浏览器在不支持 AVIF 时也会发送通配符，按规范匹配就会把 AVIF 发给无法解码的浏览器，
+/// 调用方应当在没有匹配时回退到一个通用的格式。
+pub fn preferred_types<'a>(headers: &HeaderMap, available: &[&'a str]) -> Vec<&'a str> {
+	let value = match headers.get(ACCEPT).and_then(|v| v.to_str().ok()) {
+		Some(value) => value,
+		None => return Vec::new(),
+	};
+
+	let accepts = parse_qvalues(value);
+	return rank(available, |mime| {
+		accepts.iter()
+			.find(|(name, _)| name.eq_ignore_ascii_case(mime))
+			.map(|(_, q)| *q)
+			.unwrap_or(0.0)
+	});
+}
+
+/// 检查 MIME 类型是否匹配模式，模式可以是完整的类型，也可以是 `image/*` 这样的通配，
+/// 类型中的参数（如 `; charset=utf-8`）会被忽略。
+pub fn match_mime(pattern: &str, mime: &str) -> bool {
+	let essence = mime.split(';').next().unwrap_or("").trim();
+	match pattern.strip_suffix("/*") {
+		Some("*") => true,
+		Some(top) => essence.split('/').next().is_some_and(|t| t.eq_ignore_ascii_case(top)),
+		None => pattern.eq_ignore_ascii_case(essence),
+	}
+}
+
+fn rank<'a>(available: &[&'a str], weight: impl Fn(&str) -> f32) -> Vec<&'a str> {
+	let mut items: Vec<_> = available.iter()
+		.map(|item| (*item, weight(item)))
+		.filter(|(_, q)| *q > 0.0)
+		.collect();
+
+	// sort_by 是稳定排序，权重相同时保持服务端的偏好。
+	items.sort_by(|a, b| b.1.total_cmp(&a.1));
+	return items.into_iter().map(|(item, _)| item).collect();
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::http::HeaderMap;
+
+	use crate::negotiate::{match_mime, parse_qvalues, preferred_encodings, preferred_types};
+
+	fn encodings(value: &str) -> Vec<&'static str> {
+		let mut headers = HeaderMap::new();
+		headers.append("Accept-Encoding", value.try_into().unwrap());
+		preferred_encodings(&headers, &["br", "gzip"])
+	}
+
+	#[test]
+	fn qvalues() {
+		let items = parse_qvalues("br;q=0.5, gzip , *;q=0, deflate;q=foo,;q=1");
+		assert_eq!(items, vec![("br", 0.5), ("gzip", 1.0), ("*", 0.0)]);
+	}
+
+	#[test]
+	fn encoding_order() {
+		assert_eq!(encodings("gzip, deflate, br"), vec!["br", "gzip"]);
+		assert_eq!(encodings("br;q=0.8, gzip"), vec!["gzip", "br"]);
+		assert_eq!(encodings("GZIP"), vec!["gzip"]);
+	}
+
+	#[test]
+	fn encoding_wildcard() {
+		assert_eq!(encodings("*"), vec!["br", "gzip"]);
+		assert_eq!(encodings("*, br;q=0"), vec!["gzip"]);
+		assert_eq!(encodings("identity"), Vec::<&str>::new());
+	}
+
+	#[test]
+	fn types_explicit_only() {
+		let mut headers = HeaderMap::new();
+		headers.append("Accept", "image/webp,image/png;q=0.9,image/*;q=0.8,*/*;q=0.5".try_into().unwrap());
+
+		let available = ["image/avif", "image/png", "image/webp"];
+		assert_eq!(preferred_types(&headers, &available), vec!["image/webp", "image/png"]);
+	}
+
+	#[test]
+	fn mime_patterns() {
+		assert!(match_mime("image/*", "image/png"));
+		assert!(match_mime("text/html", "text/HTML; charset=utf-8"));
+		assert!(match_mime("*/*", "video/mp4"));
+		assert!(!match_mime("image/*", "text/html"));
+		assert!(!match_mime("image/svg+xml", "image/svg"));
+	}
+
+	#[test]
+	fn no_accept_encoding() {
+		assert!(preferred_encodings(&HeaderMap::new(), &["br"]).is_empty());
+	}
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use axum::extract::State;
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
+use axum::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use base64::{Engine as _, engine::general_purpose};
+use hmac::{Hmac, Mac};
+use percent_encoding::{NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::context::OSSContext;
+use crate::db::unix_time;
+
+/*
+ * 预签名 URL，让没有登录的客户端在限定的时间内执行一个特定的请求，
+ * 比如浏览器直接上传到存储桶，或者分享私有存储桶里的对象。
+ *
+ * 签名是服务端密钥对 方法、路径、过期时间、大小和类型限制 的 HMAC-SHA256，
+ * 放在 URL 的查询参数里，其它的查询参数不参与签名。
+ */
+
+/// 签名的最长有效期，与 S3 相同为 7 天。
+const MAX_EXPIRES: u64 = 7 * 86400;
+
+const DEFAULT_EXPIRES: u64 = 3600;
+
+/// 计算和检查签名，密钥只在服务端保存。
+#[derive(Clone)]
+pub struct Presigner {
+	key: Arc<[u8]>,
+}
+
+/// 密钥文件只有所有者能读写，创建时就设置好权限，不会有短暂的可读窗口，也不会覆盖已有的文件。
+fn write_key(file: &Path, key: &[u8]) -> io::Result<()> {
+	let mut options = OpenOptions::new();
+	options.write(true).create_new(true);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::OpenOptionsExt;
+		options.mode(0o600);
+	}
+	return options.open(file)?.write_all(key);
+}
+
+impl Presigner {
+
+	/// 使用配置的密钥，没有配置时从文件读取，文件不存在则随机生成一个并保存，
+	/// 这样重启后之前签发的 URL 仍然有效。
+	pub fn load(secret: Option<&str>, file: &Path) -> io::Result<Self> {
+		if let Some(secret) = secret {
+			return Ok(Presigner { key: secret.as_bytes().into() });
+		}
+		let key = match fs::read(file) {
+			Ok(key) => key,
+			Err(e) if e.kind() == ErrorKind::NotFound => {
+				let mut key = vec![0; 32];
+				rand::thread_rng().fill_bytes(&mut key);
+				match write_key(file, &key) {
+					Ok(_) => key,
+
+					// 另一个进程同时生成了密钥，使用它的。
+					Err(e) if e.kind() == ErrorKind::AlreadyExists => fs::read(file)?,
+					Err(e) => return Err(e),
+				}
+			}
+			Err(e) => return Err(e),
+		};
+		return Ok(Presigner { key: key.into() });
+	}
+
+	fn mac(&self, grant: &Grant) -> Hmac<Sha256> {
+		let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+		mac.update(grant.canonical().as_bytes());
+		return mac;
+	}
+
+	/// 返回需要附加到 URL 的查询参数，不含开头的 `?`。
+	pub fn sign(&self, grant: &Grant) -> String {
+		let mut query = format!("expires={}", grant.expires);
+		if let Some(size) = grant.max_size {
+			query.push_str(&format!("&max_size={}", size));
+		}
+		if let Some(mime) = &grant.content_type {
+			query.push_str(&format!("&content_type={}", utf8_percent_encode(mime, NON_ALPHANUMERIC)));
+		}
+		query.push_str("&signature=");
+		query.push_str(&general_purpose::URL_SAFE_NO_PAD.encode(self.mac(grant).finalize().into_bytes()));
+		return query;
+	}
+
+	/// 检查请求是否符合签名，`uri` 必须是挂载前的完整 URI。
+	pub fn verify(&self, method: &Method, uri: &Uri, headers: &HeaderMap, now: u64) -> Result<(), PresignError> {
+		let params = parse_query(uri.query().unwrap_or(""));
+		let Some(signature) = params.get("signature") else {
+			return Err(PresignError::Missing);
+		};
+
+		let number = |key: &str| match params.get(key) {
+			None => Ok(None),
+			Some(value) => value.parse().map(Some).map_err(|_| PresignError::Mismatch),
+		};
+
+		// HEAD 与 GET 的结果相同，允许用 GET 的签名。
+		let method = match *method {
+			Method::HEAD => Method::GET,
+			_ => method.clone(),
+		};
+		let grant = Grant {
+			method: method.to_string(),
+			path: uri.path().to_owned(),
+			expires: number("expires")?.ok_or(PresignError::Mismatch)?,
+			max_size: number("max_size")?,
+			content_type: params.get("content_type").cloned(),
+		};
+
+		let signature = general_purpose::URL_SAFE_NO_PAD.decode(signature).map_err(|_| PresignError::Mismatch)?;
+		self.mac(&grant).verify_slice(&signature).map_err(|_| PresignError::Mismatch)?;
+
+		if grant.expires < now {
+			return Err(PresignError::Expired);
+		}
+		if let Some(max) = grant.max_size {
+			let length = headers.get(CONTENT_LENGTH)
+				.and_then(|v| v.to_str().ok())
+				.and_then(|v| v.parse::<u64>().ok())
+				.ok_or(PresignError::LengthRequired)?;
+			if length > max {
+				return Err(PresignError::TooLarge);
+			}
+		}
+		if let Some(expected) = &grant.content_type {
+			let actual = headers.get(CONTENT_TYPE)
+				.and_then(|v| v.to_str().ok())
+				.map(|v| v.split(';').next().unwrap().trim());
+			if !actual.is_some_and(|v| v.eq_ignore_ascii_case(expected)) {
+				return Err(PresignError::Type);
+			}
+		}
+		return Ok(());
+	}
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+	return query.split('&')
+		.filter_map(|pair| pair.split_once('='))
+		.map(|(key, value)| (key.to_owned(), percent_decode_str(value).decode_utf8_lossy().into_owned()))
+		.collect();
+}
+
+/// 签名所授权的请求，路径是 URL 中的原始形式（未解码）。
+pub struct Grant {
+	pub method: String,
+	pub path: String,
+
+	/// 过期的时间戳（秒）。
+	pub expires: u64,
+
+	/// 限制请求体的大小，请求必须带有 Content-Length。
+	pub max_size: Option<u64>,
+
+	/// 限制请求的 Content-Type，不含参数。
+	pub content_type: Option<String>,
+}
+
+impl Grant {
+
+	fn canonical(&self) -> String {
+		let max_size = self.max_size.map(|v| v.to_string()).unwrap_or_default();
+		let content_type = self.content_type.as_deref().unwrap_or("");
+		return format!("{}\n{}\n{}\n{}\n{}", self.method, self.path, self.expires, max_size, content_type);
+	}
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PresignError {
+	Missing,
+	Mismatch,
+	Expired,
+	LengthRequired,
+	TooLarge,
+	Type,
+}
+
+impl IntoResponse for PresignError {
+	fn into_response(self) -> Response {
+		match self {
+			PresignError::Missing | PresignError::Mismatch | PresignError::Expired | PresignError::Type => {
+				StatusCode::FORBIDDEN.into_response()
+			}
+			PresignError::LengthRequired => StatusCode::LENGTH_REQUIRED.into_response(),
+			PresignError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+		}
+	}
+}
+
+#[derive(Deserialize)]
+pub struct PresignRequest {
+	method: String,
+	path: String,
+
+	/// 有效期（秒），默认 1 小时。
+	expires: Option<u64>,
+	max_size: Option<u64>,
+	content_type: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PresignVO {
+	url: String,
+	expires: u64,
+}
+
+/// 签发预签名 URL，只支持下载（GET）和上传（POST），返回的是不含域名的路径。
+pub async fn presign(State(ctx): State<OSSContext>, Json(body): Json<PresignRequest>) -> Response {
+	let method = body.method.to_ascii_uppercase();
+	if method != "GET" && method != "POST" {
+		return (StatusCode::BAD_REQUEST, "Only GET and POST can be presigned").into_response();
+	}
+	if !body.path.starts_with('/') || body.path.contains(['?', '#']) {
+		return (StatusCode::BAD_REQUEST, "Invalid path").into_response();
+	}
+
+	let expires = body.expires.unwrap_or(DEFAULT_EXPIRES);
+	if expires > MAX_EXPIRES {
+		return (StatusCode::BAD_REQUEST, "Expiration is too long").into_response();
+	}
+
+	let grant = Grant {
+		method,
+		expires: unix_time(SystemTime::now()) as u64 + expires,
+		max_size: body.max_size,
+		content_type: body.content_type.filter(|v| !v.is_empty()),
+		path: body.path,
+	};
+	let url = format!("{}?{}", grant.path, ctx.presigner.sign(&grant));
+	return Json(PresignVO { url, expires: grant.expires }).into_response();
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::http::{HeaderMap, Method, Uri};
+
+	use crate::presign::{Grant, PresignError, Presigner};
+
+	fn presigner() -> Presigner {
+		return Presigner { key: b"secret".as_slice().into() };
+	}
+
+	fn sign(method: &str, max_size: Option<u64>, content_type: Option<&str>) -> Uri {
+		let grant = Grant {
+			method: method.into(),
+			path: "/s/image/abc".into(),
+			expires: 1000,
+			max_size,
+			content_type: content_type.map(str::to_owned),
+		};
+		let url = format!("/s/image/abc?{}&codec=av1", presigner().sign(&grant));
+		return url.parse().unwrap();
+	}
+
+	fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		for (name, value) in pairs {
+			headers.insert(*name, value.parse().unwrap());
+		}
+		return headers;
+	}
+
+	#[test]
+	fn generated_key() {
+		let dir = tempfile::tempdir().unwrap();
+		let file = dir.path().join("presign.key");
+		let first = Presigner::load(None, &file).unwrap();
+		assert_eq!(first.key.len(), 32);
+
+		// 重启后读取同一个密钥。
+		let second = Presigner::load(None, &file).unwrap();
+		assert_eq!(first.key, second.key);
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+			assert_eq!(mode & 0o777, 0o600);
+		}
+	}
+
+	#[test]
+	fn download() {
+		let uri = sign("GET", None, None);
+		let signer = presigner();
+		assert_eq!(signer.verify(&Method::GET, &uri, &HeaderMap::new(), 900), Ok(()));
+		assert_eq!(signer.verify(&Method::HEAD, &uri, &HeaderMap::new(), 900), Ok(()));
+		assert_eq!(signer.verify(&Method::POST, &uri, &HeaderMap::new(), 900), Err(PresignError::Mismatch));
+		assert_eq!(signer.verify(&Method::GET, &uri, &HeaderMap::new(), 1001), Err(PresignError::Expired));
+	}
+
+	#[test]
+	fn tampered() {
+		let uri = sign("GET", None, None).to_string();
+		let signer = presigner();
+
+		let other: Uri = uri.replace("/abc", "/abd").parse().unwrap();
+		assert_eq!(signer.verify(&Method::GET, &other, &HeaderMap::new(), 0), Err(PresignError::Mismatch));
+
+		let other: Uri = uri.replace("expires=1000", "expires=9999").parse().unwrap();
+		assert_eq!(signer.verify(&Method::GET, &other, &HeaderMap::new(), 0), Err(PresignError::Mismatch));
+
+		let other: Uri = "/s/image/abc".parse().unwrap();
+		assert_eq!(signer.verify(&Method::GET, &other, &HeaderMap::new(), 0), Err(PresignError::Missing));
+	}
+
+	#[test]
+	fn upload_limits() {
+		let uri = sign("POST", Some(100), Some("image/png"));
+		let signer = presigner();
+		let verify = |h: &HeaderMap| signer.verify(&Method::POST, &uri, h, 0);
+
+		let ok = headers(&[("content-length", "100"), ("content-type", "image/PNG; x=y")]);
+		assert_eq!(verify(&ok), Ok(()));
+
+		let large = headers(&[("content-length", "101"), ("content-type", "image/png")]);
+		assert_eq!(verify(&large), Err(PresignError::TooLarge));
+
+		let chunked = headers(&[("content-type", "image/png")]);
+		assert_eq!(verify(&chunked), Err(PresignError::LengthRequired));
+
+		let other = headers(&[("content-length", "1"), ("content-type", "image/jpeg")]);
+		assert_eq!(verify(&other), Err(PresignError::Type));
+	}
+}
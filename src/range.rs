@@ -1,31 +1,39 @@
 use std::fs::Metadata;
-use std::future::Future;
 use std::io;
 use std::io::{ErrorKind, SeekFrom};
 use std::ops::RangeInclusive;
 use std::path::Path;
-use std::time::SystemTime;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use axum::body::{Empty, HttpBody, StreamBody};
-use axum::http::{HeaderMap, StatusCode};
+use axum::body::{BoxBody, Bytes, Empty, HttpBody, StreamBody};
+use axum::http::{HeaderMap, HeaderName, StatusCode};
 use axum::http::header::{
 	ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE,
-	CONTENT_TYPE, ETAG, IF_NONE_MATCH,
-	IF_UNMODIFIED_SINCE, LAST_MODIFIED, RANGE,
+	CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+	IF_RANGE, IF_UNMODIFIED_SINCE, LAST_MODIFIED, RANGE, VARY,
 };
 use axum::http::response::Builder;
 use axum::response::{IntoResponse, Response};
-use futures::{AsyncRead, FutureExt, StreamExt, TryFutureExt};
+use futures::{stream, Stream};
 use http_range_header::parse_range_header;
-use httpdate::{fmt_http_date, parse_http_date};
+use httpdate::{fmt_http_date, HttpDate};
+use rand::distributions::{Alphanumeric, DistString};
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncSeekExt, Take};
 use tokio_util::io::ReaderStream;
 
+use crate::compress::{self, CompressOptions};
+
 pub enum FileCache {
+	#[allow(dead_code)]
 	None,
 	Hashed(String),
 	Modified,
+
+	/// 预压缩的文件（如 `app.js.br`），用它自己的修改时间、大小和编码生成 ETag，
+	/// 这样不同编码的表示不会共用验证器，条件请求也就不会拿到另一种编码的 304。
+	Encoded(&'static str),
 }
 
 pub enum CacheIdentifier {
@@ -40,6 +48,9 @@ pub struct FileRangeReadr {
 
 	pub mime: String,
 	pub cache: CacheIdentifier,
+
+	/// 设置后，在请求整个文件时可能会动态压缩，默认为 None 即不压缩。
+	pub compress: Option<Arc<CompressOptions>>,
 }
 
 impl FileRangeReadr {
@@ -54,9 +65,16 @@ impl FileRangeReadr {
 				Err(_) => CacheIdentifier::None,
 				Ok(time) => CacheIdentifier::Modified(time),
 			},
+			FileCache::Encoded(encoding) => match metadata.modified() {
+				Err(_) => CacheIdentifier::None,
+				Ok(time) => {
+					let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+					CacheIdentifier::Etag(format!("{:x}-{:x}-{}", secs, metadata.len(), encoding))
+				}
+			},
 		};
 
-		return Ok(FileRangeReadr { file, metadata, cache, mime });
+		return Ok(FileRangeReadr { file, metadata, cache, mime, compress: None });
 	}
 
 	pub fn size(&self) -> u64 {
@@ -67,6 +85,10 @@ impl FileRangeReadr {
 		return ReaderStream::new(self.file);
 	}
 
+	pub fn get_compressed(self, encoding: &str) -> impl Stream<Item=io::Result<Bytes>> {
+		return compress::encode(self.file, encoding);
+	}
+
 	pub async fn get_range(mut self, range: RangeInclusive<u64>) -> ReaderStream<Take<File>> {
 		let size = range.end() - range.start() + 1;
 
@@ -74,8 +96,50 @@ impl FileRangeReadr {
 		self.file.seek(SeekFrom::Start(*range.start())).await.unwrap();
 		return ReaderStream::new(self.file.take(size));
 	}
+
+	/// 依次读取多个区间，每段前面插入对应的头部，最后输出 `tail`。
+	/// 每次只读一个块，不会把整个响应缓冲到内存。
+	pub fn get_parts(
+		self,
+		parts: Vec<(Bytes, RangeInclusive<u64>)>,
+		tail: Bytes,
+	) -> impl Stream<Item=io::Result<Bytes>> {
+		const CHUNK_SIZE: u64 = 64 * 1024;
+
+		let state = (self.file, parts.into_iter(), Some(tail), 0u64);
+
+		stream::unfold(state, |(mut file, mut parts, tail, remaining)| async move {
+			if remaining > 0 {
+				let mut buf = vec![0; remaining.min(CHUNK_SIZE) as usize];
+				return match file.read(&mut buf).await {
+					Ok(0) => {
+						let e = io::Error::from(ErrorKind::UnexpectedEof);
+						Some((Err(e), (file, parts, None, 0)))
+					}
+					Ok(n) => {
+						buf.truncate(n);
+						let left = remaining - n as u64;
+						Some((Ok(Bytes::from(buf)), (file, parts, tail, left)))
+					}
+					Err(e) => Some((Err(e), (file, parts, None, 0))),
+				};
+			}
+
+			match parts.next() {
+				Some((head, range)) => {
+					let size = range.end() - range.start() + 1;
+					match file.seek(SeekFrom::Start(*range.start())).await {
+						Ok(_) => Some((Ok(head), (file, parts, tail, size))),
+						Err(e) => Some((Err(e), (file, Vec::new().into_iter(), None, 0))),
+					}
+				}
+				None => tail.map(|t| (Ok(t), (file, parts, None, 0))),
+			}
+		})
+	}
 }
 
+#[allow(dead_code)]
 pub async fn send_file(
 	path: impl AsRef<Path>,
 	headers: &HeaderMap,
@@ -93,76 +157,237 @@ pub async fn send_file(
 	}
 }
 
-/// 发送一个文件，支持 206 Partial Content。
-/// 暂时无法发送多段，因为实现起来复杂些，而且没见过这种请求，如果遇到了再考虑。
+/// 发送一个文件，支持 206 Partial Content，多个区间时使用 multipart/byteranges 格式。
 ///
 /// https://tools.ietf.org/html/rfc7233#section-4.1
 ///
 /// 代码参考了：
 /// https://github.com/tower-rs/tower-http/blob/master/tower-http/src/services/fs/serve_dir/future.rs
 ///
-pub async fn send_range(headers: &HeaderMap, mut reader: FileRangeReadr) -> Response {
+pub async fn send_range(headers: &HeaderMap, reader: FileRangeReadr) -> Response {
 	let mut builder = Response::builder().header(ACCEPT_RANGES, "bytes");
 
+	// 范围请求总是基于原始内容，否则 Content-Range 就对不上文件了。
+	let compressible = reader.compress.as_ref()
+		.filter(|c| c.is_compressible(&reader.mime, reader.size()));
+	let encoding = compressible
+		.filter(|_| !headers.contains_key(RANGE))
+		.and_then(|c| c.negotiate(headers));
+
+	if compressible.is_some() {
+		builder = builder.header(VARY, "Accept-Encoding");
+	}
+
 	// Cache-Control is added by middleware,
 	builder = match &reader.cache {
-
+		// 压缩后的内容与原文件字节不同，只能用弱 ETag。
 		// https://developer.mozilla.org/docs/Web/HTTP/Headers/ETag
-		CacheIdentifier::Etag(value) => {
-			let x = headers.get(IF_NONE_MATCH)
-				.and_then(|v| v.to_str().ok());
-
-			if x == Some(&value) {
-				return StatusCode::NOT_MODIFIED.into_response();
-			}
-			builder.header(ETAG, value)
-		}
+		CacheIdentifier::Etag(hash) if encoding.is_some() => builder.header(ETAG, format!("W/\"{}\"", hash)),
+		CacheIdentifier::Etag(hash) => builder.header(ETAG, format!("\"{}\"", hash)),
 
 		// https://developer.mozilla.org/docs/Web/HTTP/Headers/Last-Modified
-		CacheIdentifier::Modified(time) => {
-			let x = headers.get(IF_UNMODIFIED_SINCE)
-				.and_then(|v| v.to_str().ok())
-				.and_then(|v| parse_http_date(v).ok());
-
-			if x == Some(*time) {
-				return StatusCode::NOT_MODIFIED.into_response();
-			}
-			builder.header(LAST_MODIFIED, fmt_http_date(*time))
-		}
+		CacheIdentifier::Modified(time) => builder.header(LAST_MODIFIED, fmt_http_date(*time)),
 
 		CacheIdentifier::None => builder, // Cache disabled or not available.
 	};
 
-	if let Some(value) = headers.get(RANGE) {
+	if let Some(status) = precondition(headers, &reader.cache) {
+		return builder.status(status).body(empty_body()).unwrap();
+	}
+
+	if let Some(encoding) = encoding {
+		// 压缩后的长度未知，只能用 chunked 传输。
+		return builder
+			.header(CONTENT_TYPE, &reader.mime)
+			.header(CONTENT_ENCODING, encoding)
+			.body(StreamBody::new(reader.get_compressed(encoding)).boxed_unsync()).unwrap();
+	}
+
+	let range = headers.get(RANGE).filter(|_| if_range(headers, &reader.cache));
+
+	if let Some(value) = range {
 		// Use option chain to handle various type of errors.
 		let ranges = value.to_str().ok()
 			.map(|s| s.to_owned())
 			.and_then(|s| parse_range_header(&s).ok())
 			.and_then(|r| r.validate(reader.size()).ok());
 
-		if let Some(ranges) = ranges {
-			if ranges.len() == 1 {
+		match ranges.map(coalesce) {
+			Some(ranges) if ranges.len() == 1 => {
 				return single(builder, reader, ranges[0].to_owned()).await;
 			}
-			log::warn!("Can not handle request with multiple ranges.")
+			Some(ranges) if ranges.len() <= MAX_RANGES => {
+				return multiple(builder, reader, ranges);
+			}
+			// 区间太多时忽略 Range，发送完整的内容，RFC 允许这么做。
+			Some(_) => {}
+
+			// Ranges parsing failed，or has unsatisfied value.
+			None => {
+				return builder
+					.status(StatusCode::RANGE_NOT_SATISFIABLE)
+					.header(CONTENT_RANGE, format!("bytes */{}", reader.size()))
+					.body(empty_body()).unwrap();
+			}
 		}
+	}
 
-		// Ranges parsing failed，or has unsatisfied value.
-		let empty = Empty::new().map_err(|e| match e {});
-		builder
-			.status(StatusCode::RANGE_NOT_SATISFIABLE)
-			.header(CONTENT_RANGE, format!("bytes */{}", reader.size()))
-			.body(empty.boxed_unsync()).unwrap()
-	} else {
-		// No Range header in the request，send whole file.
-		builder
-			.header(CONTENT_LENGTH, reader.size())
-			.header(CONTENT_TYPE, &reader.mime)
-			.body(StreamBody::new(reader.get_whole()).boxed_unsync()).unwrap()
+	// No Range header in the request，send whole file.
+	builder
+		.header(CONTENT_LENGTH, reader.size())
+		.header(CONTENT_TYPE, &reader.mime)
+		.body(StreamBody::new(reader.get_whole()).boxed_unsync()).unwrap()
+}
+
+/// 多段响应最多包含的区间数，大量的小区间会放大响应和 CPU 开销。
+const MAX_RANGES: usize = 16;
+
+/// 合并相邻的区间，重叠的区间在解析时已经拒绝了。
+/// 没有可以合并的区间时保持请求中的顺序，否则按起始位置排序。
+///
+/// https://tools.ietf.org/html/rfc7233#section-6.1
+fn coalesce(ranges: Vec<RangeInclusive<u64>>) -> Vec<RangeInclusive<u64>> {
+	let mut sorted = ranges.clone();
+	sorted.sort_by_key(|r| *r.start());
+
+	let mut merged: Vec<RangeInclusive<u64>> = Vec::with_capacity(sorted.len());
+	for range in sorted {
+		match merged.last_mut() {
+			Some(last) if *range.start() <= last.end().saturating_add(1) => {
+				*last = *last.start()..=*last.end().max(range.end());
+			}
+			_ => merged.push(range),
+		}
+	}
+
+	return if merged.len() == ranges.len() { ranges } else { merged };
+}
+
+fn empty_body() -> BoxBody {
+	Empty::new().map_err(|e| match e {}).boxed_unsync()
+}
+
+/// 按照 RFC 规定的顺序检查条件请求头，返回 Some 表示应该直接以该状态码响应。
+/// 本函数只用于 GET 和 HEAD 请求，所以 If-None-Match 匹配时总是返回 304。
+///
+/// https://tools.ietf.org/html/rfc7232#section-6
+fn precondition(headers: &HeaderMap, cache: &CacheIdentifier) -> Option<StatusCode> {
+	let etag = match cache {
+		CacheIdentifier::Etag(hash) => Some(hash.as_str()),
+		_ => None,
+	};
+	let modified = match cache {
+		CacheIdentifier::Modified(time) => Some(HttpDate::from(*time)),
+		_ => None,
+	};
+
+	// 1) If-Match 使用强比较，不匹配时返回 412。
+	if let Some(value) = header_str(headers, IF_MATCH) {
+		if !match_etags(value, etag, false) {
+			return Some(StatusCode::PRECONDITION_FAILED);
+		}
+	} else if let (Some(date), Some(modified)) = (header_date(headers, IF_UNMODIFIED_SINCE), modified) {
+		// 2) 没有 If-Match 时才检查 If-Unmodified-Since。
+		if modified > date {
+			return Some(StatusCode::PRECONDITION_FAILED);
+		}
+	}
+
+	// 3) If-None-Match 使用弱比较，匹配时返回 304。
+	if let Some(value) = header_str(headers, IF_NONE_MATCH) {
+		if match_etags(value, etag, true) {
+			return Some(StatusCode::NOT_MODIFIED);
+		}
+	} else if let (Some(date), Some(modified)) = (header_date(headers, IF_MODIFIED_SINCE), modified) {
+		// 4) 没有 If-None-Match 时才检查 If-Modified-Since。
+		if modified <= date {
+			return Some(StatusCode::NOT_MODIFIED);
+		}
+	}
+
+	None
+}
+
+/// 检查 If-Range 头，返回 false 表示文件已改变，应当忽略 Range 发送完整的内容。
+/// If-Range 只能使用强验证器，所以弱 ETag 总是视为不匹配。
+///
+/// https://tools.ietf.org/html/rfc7233#section-3.2
+fn if_range(headers: &HeaderMap, cache: &CacheIdentifier) -> bool {
+	let value = match header_str(headers, IF_RANGE) {
+		None => return true,
+		Some(value) => value,
+	};
+
+	match cache {
+		CacheIdentifier::Etag(hash) => {
+			let tags = parse_etags(value).unwrap_or_default();
+			matches!(tags.as_slice(), [(false, tag)] if tag == hash)
+		}
+		CacheIdentifier::Modified(time) => {
+			// HTTP 日期只精确到秒，比较前需要把文件的修改时间也截断。
+			value.parse::<HttpDate>().ok() == Some(HttpDate::from(*time))
+		}
+		CacheIdentifier::None => false,
 	}
 }
 
-async fn single(builder: Builder, mut reader: FileRangeReadr, x: RangeInclusive<u64>) -> Response {
+fn header_str(headers: &HeaderMap, name: HeaderName) -> Option<&str> {
+	headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim)
+}
+
+/// 无效的日期应当被忽略，就像没有这个头一样。
+fn header_date(headers: &HeaderMap, name: HeaderName) -> Option<HttpDate> {
+	header_str(headers, name).and_then(|v| v.parse().ok())
+}
+
+/// 检查 If-Match 或 If-None-Match 的值是否与 ETag 匹配，`*` 匹配任何存在的文件。
+/// 格式错误的值视为不匹配。
+///
+/// https://tools.ietf.org/html/rfc7232#section-2.3.2
+fn match_etags(value: &str, etag: Option<&str>, weak: bool) -> bool {
+	if value == "*" {
+		return true;
+	}
+	let etag = match etag {
+		Some(etag) => etag,
+		None => return false,
+	};
+	match parse_etags(value) {
+		None => false,
+		Some(tags) => tags.iter().any(|(w, tag)| (weak || !w) && *tag == etag),
+	}
+}
+
+/// 解析逗号分隔的 ETag 列表，返回 (是否为弱验证器, 引号中的值) 的数组。
+/// 引号内可以包含逗号，所以不能简单地用 split 分割。
+fn parse_etags(value: &str) -> Option<Vec<(bool, &str)>> {
+	let mut tags = Vec::new();
+	let mut rest = value;
+
+	loop {
+		rest = rest.trim_start_matches([' ', '\t', ',']);
+		if rest.is_empty() {
+			return Some(tags);
+		}
+
+		let weak = rest.starts_with("W/");
+		if weak {
+			rest = &rest[2..];
+		}
+
+		rest = rest.strip_prefix('"')?;
+		let end = rest.find('"')?;
+		tags.push((weak, &rest[..end]));
+		rest = &rest[end + 1..];
+
+		// 每个值之后必须是分隔符或结尾。
+		if !rest.is_empty() && !rest.starts_with([' ', '\t', ',']) {
+			return None;
+		}
+	}
+}
+
+async fn single(builder: Builder, reader: FileRangeReadr, x: RangeInclusive<u64>) -> Response {
 	let length = x.end() - x.start() + 1;
 
 	builder.status(StatusCode::PARTIAL_CONTENT)
@@ -172,14 +397,62 @@ async fn single(builder: Builder, mut reader: FileRangeReadr, x: RangeInclusive<
 		.body(StreamBody::new(reader.get_range(x).await).boxed_unsync()).unwrap()
 }
 
+/// 多段的响应体格式如下，每段之间和结尾都有 CRLF 分隔：
+///
+/// ```text
+/// --<boundary>
+/// Content-Type: text/plain
+/// Content-Range: bytes 80-83/475
+///
+/// <data>
+/// --<boundary>--
+/// ```
+///
+/// 各部分的长度都是已知的，所以能先算出 Content-Length 再流式发送。
+///
+/// https://tools.ietf.org/html/rfc7233#appendix-A
+fn multiple(builder: Builder, reader: FileRangeReadr, ranges: Vec<RangeInclusive<u64>>) -> Response {
+	let boundary = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+	let size = reader.size();
+
+	let mut length = 0;
+	let mut parts = Vec::with_capacity(ranges.len());
+
+	for (i, range) in ranges.into_iter().enumerate() {
+		let delimiter = if i == 0 { "" } else { "\r\n" };
+		let head = format!(
+			"{}--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+			delimiter, boundary, reader.mime, range.start(), range.end(), size
+		);
+		length += head.len() as u64 + range.end() - range.start() + 1;
+		parts.push((Bytes::from(head), range));
+	}
+
+	let tail = format!("\r\n--{}--\r\n", boundary);
+	length += tail.len() as u64;
+
+	let body = reader.get_parts(parts, Bytes::from(tail));
+
+	builder.status(StatusCode::PARTIAL_CONTENT)
+		.header(CONTENT_LENGTH, length)
+		.header(CONTENT_TYPE, format!("multipart/byteranges; boundary={}", boundary))
+		.body(StreamBody::new(body).boxed_unsync()).unwrap()
+}
+
 #[cfg(test)]
 mod tests {
 	use std::io::ErrorKind;
+	use std::sync::Arc;
 
+	use async_compression::tokio::bufread::GzipDecoder;
 	use axum::body::{BoxBody, HttpBody};
-	use axum::http::HeaderMap;
+	use axum::http::{HeaderMap, StatusCode};
+	use axum::http::header::CONTENT_TYPE;
+	use axum::response::Response;
 	use hyper::body::to_bytes;
+	use tokio::io::AsyncReadExt;
 
+	use crate::compress::CompressOptions;
 	use crate::range::{FileCache, FileRangeReadr, send_range};
 
 	const FILE: &str = "test-files/sendrange.txt";
@@ -188,6 +461,11 @@ mod tests {
 		FileRangeReadr::open(FILE, "text/plain".into(), FileCache::None).await.unwrap()
 	}
 
+	async fn hashed_stub() -> FileRangeReadr {
+		let cache = FileCache::Hashed("foobar".into());
+		FileRangeReadr::open(FILE, "text/plain".into(), cache).await.unwrap()
+	}
+
 	async fn assert_body(actual: BoxBody, expected: &[u8]) {
 		assert_eq!(to_bytes(actual).await.unwrap().as_ref(), expected);
 	}
@@ -208,7 +486,7 @@ mod tests {
 		let (p, b) = send_range(&headers, stub().await).await.into_parts();
 
 		insta::assert_debug_snapshot!(p);
-		assert_eq!(b.is_end_stream(), true);
+		assert!(b.is_end_stream());
 	}
 
 	#[tokio::test]
@@ -254,14 +532,233 @@ mod tests {
 		let mut headers = HeaderMap::new();
 		headers.append("Range", "bytes=80-83,429-472,294-304".try_into().unwrap());
 
+		let (mut p, b) = send_range(&headers, stub().await).await.into_parts();
+
+		// The boundary is random, check it separately.
+		let content_type = p.headers.remove(CONTENT_TYPE).unwrap();
+		let boundary = content_type.to_str().unwrap()
+			.strip_prefix("multipart/byteranges; boundary=")
+			.unwrap();
+
+		let expected = format!(
+			"--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 80-83/475\r\n\r\nMUST\r\n\
+			--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 429-472/475\r\n\r\nthis field will be sent in each part instead\r\n\
+			--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 294-304/475\r\n\r\nsingle-part\r\n\
+			--{0}--\r\n",
+			boundary
+		);
+
+		insta::assert_debug_snapshot!(p);
+		assert_body(b, expected.as_bytes()).await;
+	}
+
+	#[tokio::test]
+	async fn coalesce_ranges() {
+		let mut headers = HeaderMap::new();
+		headers.append("Range", "bytes=10-19,0-9,20-49".try_into().unwrap());
+
+		let (p, b) = send_range(&headers, stub().await).await.into_parts();
+		assert_eq!(p.status, StatusCode::PARTIAL_CONTENT);
+		assert_eq!(p.headers["content-range"], "bytes 0-49/475");
+		assert_body(b, &std::fs::read(FILE).unwrap()[..50]).await;
+	}
+
+	#[tokio::test]
+	async fn too_many_ranges() {
+		let value: Vec<_> = (0..20).map(|i| format!("{0}-{0}", i * 20)).collect();
+		let mut headers = HeaderMap::new();
+		headers.append("Range", format!("bytes={}", value.join(",")).try_into().unwrap());
+
 		let (p, b) = send_range(&headers, stub().await).await.into_parts();
+		assert_eq!(p.status, StatusCode::OK);
+		assert_body(b, std::fs::read(FILE).unwrap().as_slice()).await;
+	}
+
+	#[tokio::test]
+	async fn encoded_etag() {
+		let file = FileRangeReadr::open(FILE, "text/plain".into(), FileCache::Encoded("br")).await.unwrap();
+		let (p, _) = send_range(&HeaderMap::new(), file).await.into_parts();
+
+		let etag = p.headers["etag"].to_str().unwrap();
+		assert!(etag.starts_with('"') && etag.ends_with("-1db-br\""));
+		assert!(!p.headers.contains_key("last-modified"));
+	}
+
+	// ============================= If-Range =============================
+
+	async fn if_range_stub(value: &str) -> Response {
+		let mut headers = HeaderMap::new();
+		headers.append("Range", "bytes=1-3".try_into().unwrap());
+		headers.append("If-Range", value.try_into().unwrap());
+
+		let file = FileRangeReadr::open(FILE, "text/plain".into(), FileCache::Modified).await;
+		send_range(&headers, file.unwrap()).await
+	}
+
+	fn mtime() -> String {
+		httpdate::fmt_http_date(std::fs::metadata(FILE).unwrap().modified().unwrap())
+	}
+
+	#[tokio::test]
+	async fn if_range_matched() {
+		let response = if_range_stub(&mtime()).await;
+		assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+		assert_body(response.into_body(), b"f m").await;
+	}
+
+	#[tokio::test]
+	async fn if_range_changed() {
+		let response = if_range_stub("Wed, 21 Oct 2015 07:28:00 GMT").await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_body(response.into_body(), std::fs::read(FILE).unwrap().as_slice()).await;
+	}
+
+	#[tokio::test]
+	async fn if_range_etag() {
+		let mut headers = HeaderMap::new();
+		headers.append("Range", "bytes=1-3".try_into().unwrap());
+		headers.append("If-Range", "\"foobar\"".try_into().unwrap());
+
+		let response = send_range(&headers, hashed_stub().await).await;
+		assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+
+		headers.insert("If-Range", "W/\"foobar\"".try_into().unwrap());
+		let response = send_range(&headers, hashed_stub().await).await;
+		assert_eq!(response.status(), StatusCode::OK);
+	}
+
+	// ============================= compression =============================
+
+	async fn compressible_stub() -> FileRangeReadr {
+		let mut reader = stub().await;
+		reader.compress = Some(Arc::new(CompressOptions { min_size: 0, ..Default::default() }));
+		reader
+	}
+
+	#[tokio::test]
+	async fn compressed() {
+		let mut headers = HeaderMap::new();
+		headers.append("Accept-Encoding", "gzip".try_into().unwrap());
+
+		let (p, b) = send_range(&headers, compressible_stub().await).await.into_parts();
+		insta::assert_debug_snapshot!(p);
+
+		let body = to_bytes(b).await.unwrap();
+		let mut decoded = Vec::new();
+		GzipDecoder::new(body.as_ref()).read_to_end(&mut decoded).await.unwrap();
+		assert_eq!(decoded, std::fs::read(FILE).unwrap());
+	}
+
+	#[tokio::test]
+	async fn compress_skip_range() {
+		let mut headers = HeaderMap::new();
+		headers.append("Accept-Encoding", "gzip".try_into().unwrap());
+		headers.append("Range", "bytes=1-3".try_into().unwrap());
 
+		let (p, b) = send_range(&headers, compressible_stub().await).await.into_parts();
 		insta::assert_debug_snapshot!(p);
-		assert_eq!(b.is_end_stream(), true);
+		assert_body(b, b"f m").await;
+	}
+
+	#[tokio::test]
+	async fn compress_too_small() {
+		let mut headers = HeaderMap::new();
+		headers.append("Accept-Encoding", "gzip".try_into().unwrap());
+
+		let mut reader = stub().await;
+		reader.compress = Some(Arc::new(CompressOptions::default()));
+
+		let response = send_range(&headers, reader).await;
+		assert!(!response.headers().contains_key("Content-Encoding"));
+		assert!(!response.headers().contains_key("Vary"));
 	}
 
 	// ============================= caching =============================
 
+	async fn send_hashed(name: &'static str, value: &str) -> Response {
+		let mut headers = HeaderMap::new();
+		headers.append(name, value.try_into().unwrap());
+
+		let cache = FileCache::Hashed("foobar".into());
+		let file = FileRangeReadr::open(FILE, "text/plain".into(), cache).await;
+		send_range(&headers, file.unwrap()).await
+	}
+
+	async fn send_modified(headers: HeaderMap) -> StatusCode {
+		let file = FileRangeReadr::open(FILE, "text/plain".into(), FileCache::Modified).await;
+		send_range(&headers, file.unwrap()).await.status()
+	}
+
 	#[tokio::test]
-	async fn etag() {}
+	async fn etag() {
+		let (p, b) = send_range(&HeaderMap::new(), hashed_stub().await).await.into_parts();
+		insta::assert_debug_snapshot!(p);
+		assert_body(b, std::fs::read(FILE).unwrap().as_slice()).await;
+	}
+
+	#[tokio::test]
+	async fn if_none_match() {
+		let (p, b) = send_hashed("If-None-Match", "\"foobar\"").await.into_parts();
+		insta::assert_debug_snapshot!(p);
+		assert!(b.is_end_stream());
+	}
+
+	#[tokio::test]
+	async fn if_none_match_list() {
+		let response = send_hashed("If-None-Match", "\"a,b\", W/\"foobar\"").await;
+		assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+		let response = send_hashed("If-None-Match", "*").await;
+		assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+		let response = send_hashed("If-None-Match", "\"foo\", \"bar\"").await;
+		assert_eq!(response.status(), StatusCode::OK);
+
+		let response = send_hashed("If-None-Match", "foobar").await;
+		assert_eq!(response.status(), StatusCode::OK);
+	}
+
+	#[tokio::test]
+	async fn if_match() {
+		let response = send_hashed("If-Match", "\"foobar\"").await;
+		assert_eq!(response.status(), StatusCode::OK);
+
+		let response = send_hashed("If-Match", "W/\"foobar\"").await;
+		assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+		let response = send_hashed("If-Match", "\"baz\"").await;
+		assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+	}
+
+	#[tokio::test]
+	async fn if_modified_since() {
+		let mut headers = HeaderMap::new();
+		headers.append("If-Modified-Since", mtime().try_into().unwrap());
+		assert_eq!(send_modified(headers).await, StatusCode::NOT_MODIFIED);
+
+		let mut headers = HeaderMap::new();
+		headers.append("If-Modified-Since", "Wed, 21 Oct 2015 07:28:00 GMT".try_into().unwrap());
+		assert_eq!(send_modified(headers).await, StatusCode::OK);
+	}
+
+	#[tokio::test]
+	async fn if_unmodified_since() {
+		let mut headers = HeaderMap::new();
+		headers.append("If-Unmodified-Since", mtime().try_into().unwrap());
+		assert_eq!(send_modified(headers).await, StatusCode::OK);
+
+		let mut headers = HeaderMap::new();
+		headers.append("If-Unmodified-Since", "Wed, 21 Oct 2015 07:28:00 GMT".try_into().unwrap());
+		assert_eq!(send_modified(headers).await, StatusCode::PRECONDITION_FAILED);
+	}
+
+	#[tokio::test]
+	async fn if_none_match_precedence() {
+		let mut headers = HeaderMap::new();
+		headers.append("If-None-Match", "\"foobar\"".try_into().unwrap());
+		headers.append("If-Modified-Since", mtime().try_into().unwrap());
+
+		// If-None-Match 存在时 If-Modified-Since 被忽略，没有 ETag 也就不会匹配。
+		assert_eq!(send_modified(headers).await, StatusCode::OK);
+	}
 }
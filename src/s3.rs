@@ -0,0 +1,1051 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{Extension, Router};
+use axum::body::Bytes;
+use axum::extract::{BodyStream, OriginalUri, Path, Query, State};
+use axum::http::{HeaderMap, HeaderValue, Request, StatusCode};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE, ETAG, LAST_MODIFIED, X_CONTENT_TYPE_OPTIONS};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use base64::{Engine as _, engine::general_purpose};
+use diesel::prelude::*;
+use futures::StreamExt;
+use httpdate::fmt_http_date;
+use md5::{Digest, Md5};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::Deserialize;
+use tempfile::NamedTempFile;
+
+use crate::context::{HashCollision, is_allowed_type, OCTET_STREAM, OSSContext, ReceiveError, ReceiveOptions, UploadMeta};
+use crate::db::{DbResult, unix_time};
+use crate::digest::{DigestVerifier, ExpectedDigest, expected_digests};
+use crate::manual::{check_length, ManualBucket};
+use crate::range::{FileCache, FileRangeReadr, send_range};
+use crate::schema::{objects, s3_keys, s3_uploads};
+use crate::sigv4::{self, civil_from_days, encode_key, hex, parse_hex, SignatureError, UNSIGNED_PAYLOAD};
+
+/*
+ * 兼容 S3 的接口，只实现了常用工具（rclone、aws-cli 等）需要的一小部分，只支持路径风格的 URL：
+ * ListBuckets、HeadBucket、ListObjects(V1/V2)、PutObject、GetObject、HeadObject、DeleteObject，
+ * 以及分段上传的 Create、UploadPart、Complete、Abort。
+ *
+ * S3 的存储桶就是配置中的存储桶，对象仍然按内容保存，S3 的 Key 只是指向 Hash 的映射，
+ * 所以通过 S3 上传的文件也能用 Hash 访问，ETag 就是带引号的 Hash（分段的 ETag 是 MD5）。
+ * 删除 Key 只删除映射，文件可能还被其它 Key 或者直接以 Hash 引用，需要从存储桶删除。
+ */
+
+/// 未完成的分段上传在多久之后删除，与 S3 的生命周期规则常用的值相同。
+const MULTIPART_EXPIRATION: Duration = Duration::from_secs(7 * 86400);
+
+const XML_HEADER: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n";
+
+const XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// 比所有 Key 都大的字符，前缀加上它就是前缀范围的上界。
+const MAX_CHAR: char = char::MAX;
+
+/// 对应配置文件中的 `[s3]` 部分。
+#[derive(Deserialize)]
+pub struct S3Config {
+	/// 挂载的路径，默认为 `/s3`，客户端的 endpoint 要包含它。
+	pub mount: Option<String>,
+
+	/// 访问密钥，每个都能访问所有的存储桶。
+	pub keys: Vec<S3Key>,
+}
+
+#[derive(Deserialize)]
+pub struct S3Key {
+	pub access_key: String,
+	pub secret_key: String,
+}
+
+#[derive(Clone)]
+pub struct S3State {
+	buckets: Arc<HashMap<String, ManualBucket>>,
+	secrets: Arc<HashMap<String, String>>,
+}
+
+/// 通过签名验证的 Access Key，由中间件放入请求的扩展里。
+#[derive(Clone)]
+struct AccessKey(String);
+
+pub fn s3_router<OS>(config: S3Config, buckets: HashMap<String, ManualBucket>) -> Router<OS> {
+	let secrets = config.keys.into_iter().map(|k| (k.access_key, k.secret_key)).collect();
+	let state = S3State { buckets: Arc::new(buckets), secrets: Arc::new(secrets) };
+
+	return Router::new()
+		.route("/", get(list_buckets))
+		.route("/:bucket", get(list_objects).head(head_bucket))
+		.route("/:bucket/", get(list_objects).head(head_bucket))
+		.route("/:bucket/*key", get(get_object).put(put_object).post(post_object).delete(delete_object))
+		.route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+		.with_state(state);
+}
+
+async fn authenticate<B>(
+	State(state): State<S3State>,
+	OriginalUri(uri): OriginalUri,
+	mut request: Request<B>,
+	next: Next<B>,
+) -> Response {
+	let result = sigv4::verify(request.method(), &uri, request.headers(), &state.secrets, SystemTime::now());
+	let (status, code, message) = match result {
+		Ok(access_key) => {
+			request.extensions_mut().insert(AccessKey(access_key));
+			return next.run(request).await;
+		}
+		Err(SignatureError::Missing) => (StatusCode::FORBIDDEN, "AccessDenied", "Anonymous access is not allowed"),
+		Err(SignatureError::Malformed) => (StatusCode::BAD_REQUEST, "AuthorizationHeaderMalformed", "Invalid authorization"),
+		Err(SignatureError::Unsupported) => (StatusCode::NOT_IMPLEMENTED, "NotImplemented", "Only AWS4-HMAC-SHA256 without aws-chunked is supported"),
+		Err(SignatureError::UnknownKey) => (StatusCode::FORBIDDEN, "InvalidAccessKeyId", "Unknown access key"),
+		Err(SignatureError::Skewed) => (StatusCode::FORBIDDEN, "RequestTimeTooSkewed", "Request time too skewed"),
+		Err(SignatureError::Mismatch) => (StatusCode::FORBIDDEN, "SignatureDoesNotMatch", "Signature does not match"),
+	};
+	log::debug!("S3 request rejected: {}", code);
+	return s3_error(status, code, message);
+}
+
+fn escape(value: &str) -> String {
+	return value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;");
+}
+
+fn unescape(value: &str) -> String {
+	return value.replace("&quot;", "\"").replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&");
+}
+
+fn xml_response(body: String) -> Response {
+	return ([(CONTENT_TYPE, "application/xml")], body).into_response();
+}
+
+fn s3_error(status: StatusCode, code: &str, message: &str) -> Response {
+	let body = format!(
+		"{}<Error><Code>{}</Code><Message>{}</Message></Error>",
+		XML_HEADER, code, escape(message),
+	);
+	return (status, xml_response(body)).into_response();
+}
+
+fn internal_error(e: impl std::fmt::Display) -> Response {
+	log::error!("S3 request failed: {}", e);
+	return s3_error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", "Internal error");
+}
+
+fn no_such_bucket() -> Response {
+	return s3_error(StatusCode::NOT_FOUND, "NoSuchBucket", "The specified bucket does not exist");
+}
+
+fn not_implemented(message: &str) -> Response {
+	return s3_error(StatusCode::NOT_IMPLEMENTED, "NotImplemented", message);
+}
+
+fn receive_error(e: ReceiveError) -> Response {
+	match e {
+		ReceiveError::Body(_) => s3_error(StatusCode::BAD_REQUEST, "IncompleteBody", "Failed to read the body"),
+		ReceiveError::Type(mime) => {
+			log::warn!("Rejected upload of type {}", mime);
+			s3_error(StatusCode::UNSUPPORTED_MEDIA_TYPE, "InvalidArgument", "Content type is not allowed")
+		}
+		ReceiveError::Digest(message) => s3_error(StatusCode::BAD_REQUEST, "BadDigest", &message),
+		ReceiveError::TooLarge => entity_too_large(),
+		ReceiveError::Io(e) => internal_error(e),
+	}
+}
+
+fn save_error(e: Box<dyn Error + Send + Sync>) -> Response {
+	if e.is::<HashCollision>() {
+		log::error!("{}", e);
+		return s3_error(StatusCode::CONFLICT, "OperationAborted", "Hash collision");
+	}
+	return internal_error(e);
+}
+
+/// S3 的时间格式，比如 `2013-05-24T00:00:00.000Z`。
+fn iso8601(secs: i64) -> String {
+	let (y, m, d) = civil_from_days(secs.div_euclid(86400));
+	let t = secs.rem_euclid(86400);
+	return format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.000Z", y, m, d, t / 3600, t / 60 % 60, t % 60);
+}
+
+async fn list_buckets(State(state): State<S3State>) -> Response {
+	let mut names: Vec<_> = state.buckets.keys().collect();
+	names.sort();
+
+	let mut xml = format!("{}<ListAllMyBucketsResult xmlns=\"{}\">", XML_HEADER, XMLNS);
+	xml.push_str("<Owner><ID>lwoss</ID><DisplayName>lwoss</DisplayName></Owner><Buckets>");
+	for name in names {
+		let _ = write!(xml, "<Bucket><Name>{}</Name><CreationDate>{}</CreationDate></Bucket>", escape(name), iso8601(0));
+	}
+	xml.push_str("</Buckets></ListAllMyBucketsResult>");
+	return xml_response(xml);
+}
+
+async fn head_bucket(State(state): State<S3State>, Path(name): Path<String>) -> Response {
+	return match state.buckets.contains_key(&name) {
+		true => StatusCode::OK.into_response(),
+		false => no_such_bucket(),
+	};
+}
+
+struct KeyEntry {
+	key: String,
+
+	/// 记录的 ETag，没有则是对象的 Hash，见 find_key。
+	etag: String,
+	size: i64,
+	created_at: i64,
+}
+
+/// 按字节序列出 Key 大于 `after` 且以 `prefix` 开头的对象。
+fn list_keys(bucket: &ManualBucket, prefix: &str, after: &str, limit: i64) -> DbResult<Vec<KeyEntry>> {
+	let mut conn = bucket.ctx.db.get()?;
+
+	let on = objects::bucket.eq(s3_keys::bucket).and(objects::hash.eq(s3_keys::hash));
+	let mut query = s3_keys::table
+		.inner_join(objects::table.on(on))
+		.filter(s3_keys::bucket.eq(&bucket.config.name))
+		.filter(s3_keys::key.gt(after))
+		.select((s3_keys::key, s3_keys::hash, s3_keys::etag, objects::size, s3_keys::created_at))
+		.order(s3_keys::key)
+		.limit(limit)
+		.into_boxed();
+
+	// 用范围代替 LIKE，因为 SQLite 的 LIKE 不区分大小写，而且用不上主键索引。
+	if !prefix.is_empty() {
+		query = query
+			.filter(s3_keys::key.ge(prefix.to_owned()))
+			.filter(s3_keys::key.lt(format!("{}{}", prefix, MAX_CHAR)));
+	}
+
+	let rows: Vec<(String, String, Option<String>, i64, i64)> = query.load(&mut conn)?;
+	let entries = rows.into_iter().map(|(key, hash, etag, size, created_at)| {
+		KeyEntry { key, etag: etag.unwrap_or(hash), size, created_at }
+	});
+	return Ok(entries.collect());
+}
+
+/// ListObjects 的一页结果，`next` 是下一页开始的位置（不包含）。
+struct Listing {
+	contents: Vec<KeyEntry>,
+	prefixes: Vec<String>,
+	next: Option<String>,
+
+	/// 本页最后一个 Key 或者公共前缀，V1 的 NextMarker。
+	last: Option<String>,
+}
+
+/// 遍历 Key，把包含分隔符的归入公共前缀，遇到公共前缀后直接跳到它的后面，不必逐个扫过。
+fn walk_keys(bucket: &ManualBucket, prefix: &str, delimiter: &str, start: &str, max_keys: usize) -> DbResult<Listing> {
+	let mut listing = Listing { contents: Vec::new(), prefixes: Vec::new(), next: None, last: None };
+	let mut after = start.to_owned();
+	let batch = max_keys as i64 + 1;
+
+	'outer: loop {
+		let rows = list_keys(bucket, prefix, &after, batch)?;
+		let exhausted = (rows.len() as i64) < batch;
+
+		for entry in rows {
+			if listing.contents.len() + listing.prefixes.len() == max_keys {
+				listing.next = Some(after);
+				break 'outer;
+			}
+
+			let rest = &entry.key[prefix.len()..];
+			let index = match delimiter.is_empty() {
+				true => None,
+				false => rest.find(delimiter),
+			};
+			match index {
+				Some(i) => {
+					let common = format!("{}{}", prefix, &rest[..i + delimiter.len()]);
+					after = format!("{}{}", common, MAX_CHAR);
+
+					// V1 的 Marker 可能就是上一页最后的公共前缀，它已经返回过了。
+					if common.as_str() > start {
+						listing.last = Some(common.clone());
+						listing.prefixes.push(common);
+					}
+					continue 'outer;
+				}
+				None => {
+					after = entry.key.clone();
+					listing.last = Some(entry.key.clone());
+					listing.contents.push(entry);
+				}
+			}
+		}
+
+		if exhausted {
+			break;
+		}
+	}
+	return Ok(listing);
+}
+
+async fn list_objects(
+	State(state): State<S3State>,
+	Path(name): Path<String>,
+	Query(params): Query<HashMap<String, String>>,
+) -> Response {
+	let Some(bucket) = state.buckets.get(&name) else {
+		return no_such_bucket();
+	};
+
+	if params.contains_key("location") {
+		return xml_response(format!("{}<LocationConstraint xmlns=\"{}\"/>", XML_HEADER, XMLNS));
+	}
+	let supported = ["list-type", "prefix", "delimiter", "max-keys", "encoding-type",
+		"continuation-token", "start-after", "fetch-owner", "marker"];
+	if let Some(key) = params.keys().find(|k| !supported.contains(&k.as_str())) {
+		return not_implemented(&format!("Unsupported subresource: {}", key));
+	}
+
+	let v2 = params.get("list-type").is_some_and(|v| v == "2");
+	let param = |key: &str| params.get(key).map(String::as_str).unwrap_or("");
+	let (prefix, delimiter) = (param("prefix"), param("delimiter"));
+	let url_encode = param("encoding-type") == "url";
+
+	let max_keys = match params.get("max-keys").map(|v| v.parse::<usize>()) {
+		None => 1000,
+		Some(Ok(value)) => value.min(1000),
+		Some(Err(_)) => return s3_error(StatusCode::BAD_REQUEST, "InvalidArgument", "Invalid max-keys"),
+	};
+
+	let start = match (v2, params.get("continuation-token")) {
+		(true, Some(token)) => {
+			let decoded = general_purpose::URL_SAFE_NO_PAD.decode(token).ok().and_then(|v| String::from_utf8(v).ok());
+			match decoded {
+				Some(start) => start,
+				None => return s3_error(StatusCode::BAD_REQUEST, "InvalidArgument", "Invalid continuation token"),
+			}
+		}
+		(true, None) => param("start-after").to_owned(),
+		(false, _) => param("marker").to_owned(),
+	};
+
+	let listing = match walk_keys(bucket, prefix, delimiter, &start, max_keys) {
+		Ok(listing) => listing,
+		Err(e) => return internal_error(e),
+	};
+
+	let encode = |value: &str| match url_encode {
+		true => encode_key(value),
+		false => escape(value),
+	};
+
+	let mut xml = format!("{}<ListBucketResult xmlns=\"{}\">", XML_HEADER, XMLNS);
+	let _ = write!(xml, "<Name>{}</Name><Prefix>{}</Prefix><MaxKeys>{}</MaxKeys>", escape(&name), encode(prefix), max_keys);
+	let _ = write!(xml, "<IsTruncated>{}</IsTruncated>", listing.next.is_some());
+	if !delimiter.is_empty() {
+		let _ = write!(xml, "<Delimiter>{}</Delimiter>", encode(delimiter));
+	}
+	if url_encode {
+		xml.push_str("<EncodingType>url</EncodingType>");
+	}
+
+	if v2 {
+		let _ = write!(xml, "<KeyCount>{}</KeyCount>", listing.contents.len() + listing.prefixes.len());
+		if let Some(token) = params.get("continuation-token") {
+			let _ = write!(xml, "<ContinuationToken>{}</ContinuationToken>", escape(token));
+		}
+		if let Some(after) = params.get("start-after") {
+			let _ = write!(xml, "<StartAfter>{}</StartAfter>", encode(after));
+		}
+		if let Some(next) = &listing.next {
+			let token = general_purpose::URL_SAFE_NO_PAD.encode(next);
+			let _ = write!(xml, "<NextContinuationToken>{}</NextContinuationToken>", token);
+		}
+	} else {
+		let _ = write!(xml, "<Marker>{}</Marker>", encode(&start));
+		if let (Some(_), Some(last)) = (&listing.next, &listing.last) {
+			let _ = write!(xml, "<NextMarker>{}</NextMarker>", encode(last));
+		}
+	}
+
+	for entry in &listing.contents {
+		let _ = write!(
+			xml,
+			"<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>&quot;{}&quot;</ETag>\
+			<Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+			encode(&entry.key), iso8601(entry.created_at), entry.etag, entry.size,
+		);
+	}
+	for prefix in &listing.prefixes {
+		let _ = write!(xml, "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>", encode(prefix));
+	}
+	xml.push_str("</ListBucketResult>");
+	return xml_response(xml);
+}
+
+/// 查找 Key 对应的对象，返回 Hash、类型、创建时间和 ETag，旧版本写入的 Key 没有 ETag，使用 Hash。
+fn find_key(bucket: &ManualBucket, key: &str) -> DbResult<Option<(String, String, i64, String)>> {
+	let mut conn = bucket.ctx.db.get()?;
+
+	let on = objects::bucket.eq(s3_keys::bucket).and(objects::hash.eq(s3_keys::hash));
+	let row = s3_keys::table
+		.inner_join(objects::table.on(on))
+		.filter(s3_keys::bucket.eq(&bucket.config.name))
+		.filter(s3_keys::key.eq(key))
+		.select((s3_keys::hash, objects::mime, s3_keys::created_at, s3_keys::etag))
+		.first::<(String, String, i64, Option<String>)>(&mut conn)
+		.optional()?;
+	return Ok(row.map(|(hash, mime, created_at, etag)| {
+		let etag = etag.unwrap_or_else(|| hash.clone());
+		(hash, mime, created_at, etag)
+	}));
+}
+
+/// GET 的路由同时处理 HEAD 请求，axum 会去掉响应体。
+async fn get_object(
+	State(state): State<S3State>,
+	Path((name, key)): Path<(String, String)>,
+	Query(params): Query<HashMap<String, String>>,
+	headers: HeaderMap,
+) -> Response {
+	let Some(bucket) = state.buckets.get(&name) else {
+		return no_such_bucket();
+	};
+	if params.contains_key("uploadId") {
+		return not_implemented("ListParts is not supported");
+	}
+
+	let (hash, mime, created_at, etag) = match find_key(bucket, &key) {
+		Ok(Some(row)) => row,
+		Ok(None) => return s3_error(StatusCode::NOT_FOUND, "NoSuchKey", "The specified key does not exist"),
+		Err(e) => return internal_error(e),
+	};
+
+	// 与 manual::download 相同，允许列表之外的类型作为附件下载。
+	let allowed = is_allowed_type(&bucket.config.allowed_types, &mime);
+	let mime = if allowed { mime } else { OCTET_STREAM.to_owned() };
+
+	let path = bucket.ctx.data_dir.join(&hash);
+	return match FileRangeReadr::open(path, mime, FileCache::Hashed(etag)).await {
+		Ok(file) => {
+			let mut response = send_range(&headers, file).await;
+			let time = UNIX_EPOCH + Duration::from_secs(created_at as u64);
+			response.headers_mut().insert(LAST_MODIFIED, HeaderValue::from_str(&fmt_http_date(time)).unwrap());
+			if !allowed {
+				response.headers_mut().insert(CONTENT_DISPOSITION, HeaderValue::from_static("attachment"));
+			}
+			response.headers_mut().insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+			response
+		}
+		Err(e) if e.kind() == ErrorKind::NotFound => {
+			s3_error(StatusCode::NOT_FOUND, "NoSuchKey", "The specified key does not exist")
+		}
+		Err(e) => internal_error(e),
+	};
+}
+
+/// 在存储桶的接收选项上加入 x-amz-content-sha256 的检查。
+fn receive_options<'a>(bucket: &'a ManualBucket, headers: &'a HeaderMap) -> Result<ReceiveOptions<'a>, ReceiveError> {
+	let mut options = bucket.receive_options(headers)?;
+
+	// SDK 默认的类型，等同于没有声明。
+	if options.claimed_type == Some("binary/octet-stream") {
+		options.claimed_type = None;
+	}
+	options.digests.extend(payload_digest(headers)?);
+	options.md5 = true;
+	return Ok(options);
+}
+
+fn payload_digest(headers: &HeaderMap) -> Result<Option<ExpectedDigest>, ReceiveError> {
+	let value = headers.get("x-amz-content-sha256")
+		.and_then(|v| v.to_str().ok())
+		.unwrap_or(UNSIGNED_PAYLOAD);
+
+	if value == UNSIGNED_PAYLOAD {
+		return Ok(None);
+	}
+	return match parse_hex(value).filter(|v| v.len() == 32) {
+		Some(value) => Ok(Some(ExpectedDigest::sha256(value))),
+		None => Err(ReceiveError::Digest("Invalid x-amz-content-sha256".into())),
+	};
+}
+
+/// 保存文件并把 Key 指向它，同时记录返回给客户端的 ETag。
+async fn store(bucket: &ManualBucket, key: &str, buf: crate::context::FileBuf, access: &str, etag: &str) -> DbResult<()> {
+	let meta = UploadMeta {
+		bucket: bucket.config.name.clone(),
+		filename: key.rsplit('/').next().filter(|s| !s.is_empty()).map(str::to_owned),
+		uploader: Some(access.to_owned()),
+	};
+	let (object, _) = buf.save(meta, bucket.config.verify_duplicates).await?;
+
+	let mut conn = bucket.ctx.db.get()?;
+	diesel::replace_into(s3_keys::table)
+		.values((
+			s3_keys::bucket.eq(&bucket.config.name),
+			s3_keys::key.eq(key),
+			s3_keys::hash.eq(&object.hash),
+			s3_keys::created_at.eq(unix_time(SystemTime::now())),
+			s3_keys::etag.eq(etag),
+		))
+		.execute(&mut conn)?;
+
+	log::debug!("S3 key {}/{} now points to {}", bucket.config.name, key, object.hash);
+	return Ok(());
+}
+
+fn etag_response(etag: &str) -> Response {
+	let mut response = StatusCode::OK.into_response();
+	response.headers_mut().insert(ETAG, HeaderValue::from_str(&format!("\"{}\"", etag)).unwrap());
+	return response;
+}
+
+async fn put_object(
+	State(state): State<S3State>,
+	Path((name, key)): Path<(String, String)>,
+	Query(params): Query<HashMap<String, String>>,
+	Extension(AccessKey(access)): Extension<AccessKey>,
+	headers: HeaderMap,
+	body: BodyStream,
+) -> Response {
+	let Some(bucket) = state.buckets.get(&name) else {
+		return no_such_bucket();
+	};
+	if headers.contains_key("x-amz-copy-source") {
+		return not_implemented("CopyObject is not supported");
+	}
+
+	match (params.get("partNumber"), params.get("uploadId")) {
+		(Some(number), Some(id)) => return upload_part(bucket, &key, number, id, &headers, body).await,
+		(None, None) => {}
+		_ => return s3_error(StatusCode::BAD_REQUEST, "InvalidArgument", "Both partNumber and uploadId are required"),
+	}
+
+	if key.len() > 1024 {
+		return s3_error(StatusCode::BAD_REQUEST, "KeyTooLongError", "Your key is too long");
+	}
+	if check_length(&bucket.config, &headers).is_err() {
+		return entity_too_large();
+	}
+
+	let options = match receive_options(bucket, &headers) {
+		Ok(options) => options,
+		Err(e) => return receive_error(e),
+	};
+	let buf = match bucket.ctx.receive_file(body, options).await {
+		Ok(buf) => buf,
+		Err(e) => return receive_error(e),
+	};
+	// receive_options 要求计算 MD5，与 S3 一样作为 ETag。
+	let etag = hex(buf.md5.as_deref().unwrap());
+	return match store(bucket, &key, buf, &access, &etag).await {
+		Ok(_) => etag_response(&etag),
+		Err(e) => save_error(e),
+	};
+}
+
+async fn delete_object(
+	State(state): State<S3State>,
+	Path((name, key)): Path<(String, String)>,
+	Query(params): Query<HashMap<String, String>>,
+) -> Response {
+	let Some(bucket) = state.buckets.get(&name) else {
+		return no_such_bucket();
+	};
+	if let Some(id) = params.get("uploadId") {
+		return abort_upload(bucket, &key, id);
+	}
+
+	let deleted = bucket.ctx.db.get().map_err(Box::<dyn Error + Send + Sync>::from).and_then(|mut conn| {
+		let key = s3_keys::table.find((&bucket.config.name, &key));
+		Ok(diesel::delete(key).execute(&mut conn)?)
+	});
+
+	// 与 S3 相同，删除不存在的 Key 也返回成功。
+	return match deleted {
+		Ok(_) => StatusCode::NO_CONTENT.into_response(),
+		Err(e) => internal_error(e),
+	};
+}
+
+async fn post_object(
+	State(state): State<S3State>,
+	Path((name, key)): Path<(String, String)>,
+	Query(params): Query<HashMap<String, String>>,
+	Extension(AccessKey(access)): Extension<AccessKey>,
+	headers: HeaderMap,
+	body: Bytes,
+) -> Response {
+	let Some(bucket) = state.buckets.get(&name) else {
+		return no_such_bucket();
+	};
+	if params.contains_key("uploads") {
+		return create_upload(bucket, &key, &headers);
+	}
+	if let Some(id) = params.get("uploadId") {
+		return complete_upload(bucket, &key, id, &access, &body).await;
+	}
+	return not_implemented("Unsupported POST request");
+}
+
+fn entity_too_large() -> Response {
+	return s3_error(StatusCode::BAD_REQUEST, "EntityTooLarge", "Your proposed upload exceeds the maximum allowed size");
+}
+
+fn no_such_upload() -> Response {
+	return s3_error(StatusCode::NOT_FOUND, "NoSuchUpload", "The specified upload does not exist");
+}
+
+fn upload_dir(ctx: &OSSContext, id: &str) -> PathBuf {
+	return ctx.buf_dir.join("s3").join(id);
+}
+
+/// 查找分段上传，返回创建时声明的类型，存储桶和 Key 必须与创建时相同。
+fn find_upload(bucket: &ManualBucket, key: &str, id: &str) -> DbResult<Option<Option<String>>> {
+	let mut conn = bucket.ctx.db.get()?;
+	let mime = s3_uploads::table
+		.find(id)
+		.filter(s3_uploads::bucket.eq(&bucket.config.name))
+		.filter(s3_uploads::key.eq(key))
+		.select(s3_uploads::mime)
+		.first(&mut conn)
+		.optional()?;
+	return Ok(mime);
+}
+
+fn remove_upload(ctx: &OSSContext, id: &str) -> DbResult<()> {
+	let mut conn = ctx.db.get()?;
+	diesel::delete(s3_uploads::table.find(id)).execute(&mut conn)?;
+
+	match fs::remove_dir_all(upload_dir(ctx, id)) {
+		Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+		_ => Ok(()),
+	}
+}
+
+fn create_upload(bucket: &ManualBucket, key: &str, headers: &HeaderMap) -> Response {
+	let id = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+	let mime = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+
+	let created = bucket.ctx.db.get().map_err(Box::<dyn Error + Send + Sync>::from).and_then(|mut conn| {
+		fs::create_dir_all(upload_dir(&bucket.ctx, &id))?;
+		diesel::insert_into(s3_uploads::table)
+			.values((
+				s3_uploads::id.eq(&id),
+				s3_uploads::bucket.eq(&bucket.config.name),
+				s3_uploads::key.eq(key),
+				s3_uploads::mime.eq(mime),
+				s3_uploads::created_at.eq(unix_time(SystemTime::now())),
+			))
+			.execute(&mut conn)?;
+		Ok(())
+	});
+	if let Err(e) = created {
+		return internal_error(e);
+	}
+
+	let mut xml = format!("{}<InitiateMultipartUploadResult xmlns=\"{}\">", XML_HEADER, XMLNS);
+	let _ = write!(xml, "<Bucket>{}</Bucket><Key>{}</Key>", escape(&bucket.config.name), escape(key));
+	let _ = write!(xml, "<UploadId>{}</UploadId></InitiateMultipartUploadResult>", id);
+	return xml_response(xml);
+}
+
+/// 分段先写到临时文件，检查完摘要再改名，所以目录里的分段总是完整的，重传会覆盖旧的。
+async fn upload_part(
+	bucket: &ManualBucket,
+	key: &str,
+	number: &str,
+	id: &str,
+	headers: &HeaderMap,
+	mut body: BodyStream,
+) -> Response {
+	let number = match number.parse::<u32>() {
+		Ok(n) if (1..=10000).contains(&n) => n,
+		_ => return s3_error(StatusCode::BAD_REQUEST, "InvalidArgument", "Part number must be between 1 and 10000"),
+	};
+	match find_upload(bucket, key, id) {
+		Ok(Some(_)) => {}
+		Ok(None) => return no_such_upload(),
+		Err(e) => return internal_error(e),
+	}
+
+	let mut digests = match expected_digests(headers) {
+		Ok(digests) => digests,
+		Err(message) => return s3_error(StatusCode::BAD_REQUEST, "InvalidDigest", &message),
+	};
+	match payload_digest(headers) {
+		Ok(digest) => digests.extend(digest),
+		Err(e) => return receive_error(e),
+	}
+
+	// 大小限制针对整个对象，所以从已上传的其它段的大小开始累计。
+	// 并发上传的段可能都通过这里的检查，完成时 concat_parts 还会再检查一次。
+	let dir = upload_dir(&bucket.ctx, id);
+	let mut size = match stored_size(&dir, number).await {
+		Ok(size) => size,
+		Err(e) => return internal_error(e),
+	};
+	if bucket.config.max_size.is_some_and(|max| size > max) {
+		return entity_too_large();
+	}
+
+	let mut file = match NamedTempFile::new_in(&dir) {
+		Ok(file) => file,
+		Err(e) => return internal_error(e),
+	};
+	let mut verifier = DigestVerifier::new(digests);
+	let mut md5 = Md5::new();
+
+	while let Some(chunk) = body.next().await {
+		let data = match chunk {
+			Ok(data) => data,
+			Err(e) => return receive_error(ReceiveError::Body(e)),
+		};
+		size += data.len() as u64;
+		if bucket.config.max_size.is_some_and(|max| size > max) {
+			return entity_too_large();
+		}
+		verifier.update(&data);
+		md5.update(&data);
+		if let Err(e) = file.write_all(&data) {
+			return internal_error(e);
+		}
+	}
+
+	if let Err(algorithm) = verifier.verify() {
+		return s3_error(StatusCode::BAD_REQUEST, "BadDigest", &format!("{} digest mismatch", algorithm));
+	}
+	if let Err(e) = file.persist(dir.join(number.to_string())) {
+		return internal_error(e);
+	}
+	return etag_response(&hex(&md5.finalize()));
+}
+
+/// 从 CompleteMultipartUpload 的请求体中读取各段的编号和 ETag。
+///
+/// 结构很简单，就不引入 XML 库了，只按标签名查找。
+fn parse_parts(xml: &str) -> Option<Vec<(u32, String)>> {
+	let text = |part: &str, name: &str| -> Option<String> {
+		let start = part.find(&format!("<{}>", name))? + name.len() + 2;
+		let end = part[start..].find(&format!("</{}>", name))? + start;
+		Some(unescape(part[start..end].trim()))
+	};
+
+	let mut parts = Vec::new();
+	for part in xml.split("<Part>").skip(1) {
+		let part = &part[..part.find("</Part>")?];
+		let number = text(part, "PartNumber")?.parse().ok()?;
+		let etag = text(part, "ETag")?.trim_matches('"').to_owned();
+		parts.push((number, etag));
+	}
+	return Some(parts);
+}
+
+/// 已上传的段的总大小，不包括将被替换的同编号的段，上传中的临时文件也不算。
+async fn stored_size(dir: &std::path::Path, except: u32) -> io::Result<u64> {
+	let mut size = 0;
+	let mut entries = tokio::fs::read_dir(dir).await?;
+	while let Some(entry) = entries.next_entry().await? {
+		let name = entry.file_name();
+		match name.to_str().and_then(|v| v.parse::<u32>().ok()) {
+			Some(number) if number != except => size += entry.metadata().await?.len(),
+			_ => {}
+		}
+	}
+	return Ok(size);
+}
+
+/// 把各段拼接到一个临时文件中，同时检查 ETag，出错时返回 S3 的错误码。
+fn concat_parts(bucket: &ManualBucket, id: &str, parts: &[(u32, String)]) -> Result<PathBuf, (&'static str, String)> {
+	let internal = |e: std::io::Error| ("InternalError", e.to_string());
+	let dir = upload_dir(&bucket.ctx, id);
+	let mut output = NamedTempFile::new_in(&bucket.ctx.buf_dir).map_err(internal)?;
+	let mut buffer = vec![0; 65536];
+	let mut size = 0;
+
+	for (number, etag) in parts {
+		let mut file = match File::open(dir.join(number.to_string())) {
+			Ok(file) => file,
+			Err(e) if e.kind() == ErrorKind::NotFound => return Err(("InvalidPart", format!("Part {} not found", number))),
+			Err(e) => return Err(internal(e)),
+		};
+
+		let mut md5 = Md5::new();
+		loop {
+			let n = file.read(&mut buffer).map_err(internal)?;
+			if n == 0 {
+				break;
+			}
+			size += n as u64;
+			if bucket.config.max_size.is_some_and(|max| size > max) {
+				return Err(("EntityTooLarge", "Your proposed upload exceeds the maximum allowed size".into()));
+			}
+			md5.update(&buffer[..n]);
+			output.write_all(&buffer[..n]).map_err(internal)?;
+		}
+
+		if hex(&md5.finalize()) != *etag {
+			return Err(("InvalidPart", format!("ETag of part {} does not match", number)));
+		}
+	}
+
+	let (_, path) = output.keep().map_err(|e| internal(e.error))?;
+	return Ok(path);
+}
+
+/// 分段上传的对象的 ETag 与 S3 相同，是各段 MD5 拼接后的 MD5 加上段数，
+/// 各段的 ETag 在 concat_parts 中检查过，就是它们的 MD5。
+fn multipart_etag(parts: &[(u32, String)]) -> String {
+	let mut md5 = Md5::new();
+	for (_, etag) in parts {
+		md5.update(parse_hex(etag).unwrap_or_default());
+	}
+	return format!("{}-{}", hex(&md5.finalize()), parts.len());
+}
+
+async fn complete_upload(bucket: &ManualBucket, key: &str, id: &str, access: &str, body: &[u8]) -> Response {
+	let mime = match find_upload(bucket, key, id) {
+		Ok(Some(mime)) => mime,
+		Ok(None) => return no_such_upload(),
+		Err(e) => return internal_error(e),
+	};
+
+	let parts = match std::str::from_utf8(body).ok().and_then(parse_parts) {
+		Some(parts) if !parts.is_empty() => parts,
+		_ => return s3_error(StatusCode::BAD_REQUEST, "MalformedXML", "Invalid part list"),
+	};
+	if parts.windows(2).any(|w| w[0].0 >= w[1].0) {
+		return s3_error(StatusCode::BAD_REQUEST, "InvalidPartOrder", "Parts must be in ascending order");
+	}
+
+	let etag = multipart_etag(&parts);
+
+	// 拼接要读写整个文件，放到阻塞线程里，以免卡住其它请求。
+	let concat = {
+		let (bucket, id) = (bucket.clone(), id.to_owned());
+		tokio::task::spawn_blocking(move || concat_parts(&bucket, &id, &parts))
+	};
+	let path = match concat.await {
+		Ok(Ok(path)) => path,
+		Ok(Err(("InternalError", message))) => return internal_error(message),
+		Ok(Err((code, message))) => return s3_error(StatusCode::BAD_REQUEST, code, &message),
+		Err(e) => return internal_error(e),
+	};
+
+	let options = ReceiveOptions {
+		claimed_type: mime.as_deref().filter(|m| *m != "binary/octet-stream"),
+		allowed_types: &bucket.config.allowed_types,
+		hash: &bucket.config.hash,
+		digests: Vec::new(),
+		max_size: bucket.config.max_size,
+		md5: false,
+	};
+	let buf = match bucket.ctx.inspect_file(path, options).await {
+		Ok(buf) => buf,
+		Err(e) => return receive_error(e),
+	};
+	if let Err(e) = store(bucket, key, buf, access, &etag).await {
+		return save_error(e);
+	}
+
+	if let Err(e) = remove_upload(&bucket.ctx, id) {
+		log::error!("Failed to remove multipart upload {}: {}", id, e);
+	}
+
+	let mut xml = format!("{}<CompleteMultipartUploadResult xmlns=\"{}\">", XML_HEADER, XMLNS);
+	let _ = write!(xml, "<Bucket>{}</Bucket><Key>{}</Key>", escape(&bucket.config.name), escape(key));
+	let _ = write!(xml, "<ETag>&quot;{}&quot;</ETag></CompleteMultipartUploadResult>", etag);
+	return xml_response(xml);
+}
+
+fn abort_upload(bucket: &ManualBucket, key: &str, id: &str) -> Response {
+	match find_upload(bucket, key, id) {
+		Ok(Some(_)) => {}
+		Ok(None) => return no_such_upload(),
+		Err(e) => return internal_error(e),
+	}
+	return match remove_upload(&bucket.ctx, id) {
+		Ok(_) => StatusCode::NO_CONTENT.into_response(),
+		Err(e) => internal_error(e),
+	};
+}
+
+/// 定期删除过期的分段上传，所有存储桶共用一个任务。
+pub async fn expire_uploads(ctx: OSSContext) {
+	let mut interval = tokio::time::interval(Duration::from_secs(3600));
+	loop {
+		interval.tick().await;
+		match remove_expired(&ctx) {
+			Ok(0) => {}
+			Ok(count) => log::info!("Removed {} expired multipart uploads", count),
+			Err(e) => log::error!("Failed to remove expired multipart uploads: {}", e),
+		}
+	}
+}
+
+fn remove_expired(ctx: &OSSContext) -> DbResult<usize> {
+	let deadline = unix_time(SystemTime::now() - MULTIPART_EXPIRATION);
+	let ids: Vec<String> = s3_uploads::table
+		.filter(s3_uploads::created_at.le(deadline))
+		.select(s3_uploads::id)
+		.load(&mut ctx.db.get()?)?;
+
+	for id in &ids {
+		remove_upload(ctx, id)?;
+	}
+	return Ok(ids.len());
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use std::sync::Arc;
+
+	use axum::Extension;
+	use axum::extract::{Path, Query, State};
+	use axum::http::{HeaderMap, StatusCode};
+	use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE, ETAG, X_CONTENT_TYPE_OPTIONS};
+	use axum::response::Response;
+
+	use crate::context::{test_body, test_context};
+	use crate::manual::{BucketConfig, ManualBucket, test_bucket};
+	use crate::s3::{
+		AccessKey, complete_upload, create_upload, get_object, iso8601, list_keys, parse_parts, put_object,
+		S3State, upload_part,
+	};
+
+	#[test]
+	fn time_format() {
+		assert_eq!(iso8601(0), "1970-01-01T00:00:00.000Z");
+		assert_eq!(iso8601(1369353600 + 3723), "2013-05-24T01:02:03.000Z");
+	}
+
+	#[test]
+	fn complete_parts() {
+		let xml = r#"<CompleteMultipartUpload xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+			<Part><ETag>"a54357aff0632cce46d942af68356b38"</ETag><PartNumber>1</PartNumber></Part>
+			<Part><PartNumber>2</PartNumber><ETag>&quot;0c78aef83f66abc1fa1e8477f296d394&quot;</ETag></Part>
+		</CompleteMultipartUpload>"#;
+
+		let parts = parse_parts(xml).unwrap();
+		assert_eq!(parts, vec![
+			(1, "a54357aff0632cce46d942af68356b38".to_owned()),
+			(2, "0c78aef83f66abc1fa1e8477f296d394".to_owned()),
+		]);
+	}
+
+	#[test]
+	fn invalid_parts() {
+		assert!(parse_parts("<Part><PartNumber>x</PartNumber><ETag>a</ETag></Part>").is_none());
+		assert!(parse_parts("<Part><PartNumber>1</PartNumber>").is_none());
+	}
+
+	async fn put_part(bucket: &ManualBucket, id: &str, number: &str, data: &'static [u8]) -> StatusCode {
+		let body = test_body(&[data]).await;
+		return upload_part(bucket, "a.bin", number, id, &HeaderMap::new(), body).await.status();
+	}
+
+	#[tokio::test]
+	async fn part_size_total() {
+		let dir = tempfile::tempdir().unwrap();
+		let config = BucketConfig { max_size: Some(10), ..BucketConfig::default_image(dir.path()) };
+		let bucket = test_bucket(test_context(dir.path()), config);
+
+		let response = create_upload(&bucket, "a.bin", &HeaderMap::new());
+		let xml = hyper::body::to_bytes(response.into_body()).await.unwrap();
+		let xml = std::str::from_utf8(&xml).unwrap();
+		let id = xml.split("<UploadId>").nth(1).unwrap().split('<').next().unwrap();
+
+		assert_eq!(put_part(&bucket, id, "1", b"123456").await, StatusCode::OK);
+		assert_eq!(put_part(&bucket, id, "2", b"123456").await, StatusCode::BAD_REQUEST);
+
+		// 重传的段替换旧的，不重复计算。
+		assert_eq!(put_part(&bucket, id, "1", b"1234").await, StatusCode::OK);
+		assert_eq!(put_part(&bucket, id, "2", b"123456").await, StatusCode::OK);
+	}
+
+	fn s3_state(buckets: &[&ManualBucket]) -> S3State {
+		let buckets = buckets.iter().map(|b| (b.config.name.clone(), (*b).clone())).collect();
+		return S3State { buckets: Arc::new(buckets), secrets: Arc::new(HashMap::new()) };
+	}
+
+	async fn put(bucket: &ManualBucket, key: &str, data: &'static [u8]) -> Response {
+		let path = Path((bucket.config.name.clone(), key.to_owned()));
+		let access = Extension(AccessKey("test".into()));
+		let body = test_body(&[data]).await;
+		return put_object(State(s3_state(&[bucket])), path, Query(HashMap::new()), access, HeaderMap::new(), body).await;
+	}
+
+	async fn get(bucket: &ManualBucket, key: &str) -> Response {
+		let path = Path((bucket.config.name.clone(), key.to_owned()));
+		return get_object(State(s3_state(&[bucket])), path, Query(HashMap::new()), HeaderMap::new()).await;
+	}
+
+	#[tokio::test]
+	async fn md5_etag() {
+		let dir = tempfile::tempdir().unwrap();
+		let config = BucketConfig { allowed_types: vec![], ..BucketConfig::default_image(dir.path()) };
+		let bucket = test_bucket(test_context(dir.path()), config);
+
+		let response = put(&bucket, "a.txt", b"hello").await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(response.headers()[ETAG], "\"5d41402abc4b2a76b9719d911017c592\"");
+
+		let response = get(&bucket, "a.txt").await;
+		assert_eq!(response.headers()[ETAG], "\"5d41402abc4b2a76b9719d911017c592\"");
+		let entries = list_keys(&bucket, "", "", 10).unwrap();
+		assert_eq!(entries[0].etag, "5d41402abc4b2a76b9719d911017c592");
+	}
+
+	#[tokio::test]
+	async fn multipart_etag() {
+		let dir = tempfile::tempdir().unwrap();
+		let config = BucketConfig { allowed_types: vec![], ..BucketConfig::default_image(dir.path()) };
+		let bucket = test_bucket(test_context(dir.path()), config);
+
+		let response = create_upload(&bucket, "a.bin", &HeaderMap::new());
+		let xml = hyper::body::to_bytes(response.into_body()).await.unwrap();
+		let xml = std::str::from_utf8(&xml).unwrap();
+		let id = xml.split("<UploadId>").nth(1).unwrap().split('<').next().unwrap();
+
+		assert_eq!(put_part(&bucket, id, "1", b"hello").await, StatusCode::OK);
+		assert_eq!(put_part(&bucket, id, "2", b"world").await, StatusCode::OK);
+
+		let body = "<CompleteMultipartUpload>\
+			<Part><PartNumber>1</PartNumber><ETag>\"5d41402abc4b2a76b9719d911017c592\"</ETag></Part>\
+			<Part><PartNumber>2</PartNumber><ETag>\"7d793037a0760186574b0282f2f435e7\"</ETag></Part>\
+			</CompleteMultipartUpload>";
+		let response = complete_upload(&bucket, "a.bin", id, "test", body.as_bytes()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		let xml = hyper::body::to_bytes(response.into_body()).await.unwrap();
+		let expected = "065947336a2f2a95ba8899f3675c3be6-2";
+		assert!(std::str::from_utf8(&xml).unwrap().contains(&format!("<ETag>&quot;{}&quot;</ETag>", expected)));
+
+		let response = get(&bucket, "a.bin").await;
+		assert_eq!(response.headers()[ETAG], format!("\"{}\"", expected).as_str());
+	}
+
+	#[tokio::test]
+	async fn unlisted_type() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = test_context(dir.path());
+		let config = BucketConfig { allowed_types: vec![], ..BucketConfig::default_image(dir.path()) };
+		let open = test_bucket(ctx.clone(), config);
+		assert_eq!(put(&open, "a.pdf", b"%PDF-1.4 ").await.status(), StatusCode::OK);
+
+		let response = get(&open, "a.pdf").await;
+		assert_eq!(response.headers()[CONTENT_TYPE], "application/pdf");
+		assert!(response.headers().get(CONTENT_DISPOSITION).is_none());
+
+		// 收紧配置后，之前上传的对象作为附件下载。
+		let images = test_bucket(ctx, BucketConfig::default_image(dir.path()));
+		let response = get(&images, "a.pdf").await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(response.headers()[CONTENT_TYPE], "application/octet-stream");
+		assert_eq!(response.headers()[CONTENT_DISPOSITION], "attachment");
+		assert_eq!(response.headers()[X_CONTENT_TYPE_OPTIONS], "nosniff");
+	}
+}
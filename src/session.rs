@@ -0,0 +1,241 @@
+use std::time::SystemTime;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use axum_extra::extract::cookie::{Cookie, SameSite};
+use axum_extra::extract::CookieJar;
+use cookie::time::{Duration, OffsetDateTime};
+use diesel::prelude::*;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+
+use crate::context::OSSContext;
+use crate::db::{DbPool, DbResult, unix_time};
+use crate::schema::{sessions, users};
+use crate::token::hash_token;
+use crate::user::{Role, User};
+
+/*
+ * 登录后在服务端保存会话，Cookie 里只有随机的会话令牌，密码不会离开登录请求。
+ * 会话可以单独注销，Cookie 泄露时不必修改密码；删除用户或重置密码也会注销其所有会话。
+ */
+
+pub const SESSION_COOKIE: &str = "session";
+
+/// 会话的配置，对应配置文件中的 `[session]` 部分。
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct SessionOptions {
+	/// 会话的有效期（秒），默认 30 天。
+	pub lifetime: u64,
+
+	/// Cookie 是否带有 Secure 属性，默认为 true，此时浏览器只在 HTTPS 或 localhost 上发送它。
+	/// 如果通过 HTTP 访问其它地址，需要设为 false 才能登录。
+	pub secure_cookie: bool,
+}
+
+impl Default for SessionOptions {
+	fn default() -> Self {
+		SessionOptions { lifetime: 30 * 86400, secure_cookie: true }
+	}
+}
+
+/// 创建会话并返回设置了 Cookie 的 jar，同时顺便清理过期的会话。
+pub fn create_session(ctx: &OSSContext, jar: CookieJar, username: &str, user_agent: Option<&str>) -> DbResult<CookieJar> {
+	let mut rng = rand::thread_rng();
+	let token = Alphanumeric.sample_string(&mut rng, 40);
+	let now = unix_time(SystemTime::now());
+	let lifetime = ctx.session.lifetime as i64;
+
+	let mut conn = ctx.db.get()?;
+	diesel::delete(sessions::table.filter(sessions::expires_at.le(now))).execute(&mut conn)?;
+	diesel::insert_into(sessions::table)
+		.values((
+			sessions::id.eq(Alphanumeric.sample_string(&mut rng, 16)),
+			sessions::token_hash.eq(hash_token(&token)),
+			sessions::username.eq(username),
+			sessions::user_agent.eq(user_agent),
+			sessions::created_at.eq(now),
+			sessions::expires_at.eq(now + lifetime),
+		))
+		.execute(&mut conn)?;
+
+	let mut cookie = Cookie::new(SESSION_COOKIE, token);
+	cookie.set_path("/");
+	cookie.set_http_only(true);
+	cookie.set_secure(ctx.session.secure_cookie);
+	cookie.set_same_site(SameSite::Lax);
+	cookie.set_expires(OffsetDateTime::now_utc() + Duration::seconds(lifetime));
+	return Ok(jar.add(cookie));
+}
+
+/// 查找请求的会话所属的用户，没有会话或已过期时返回 None。
+pub fn check_session(db: &DbPool, jar: &CookieJar) -> DbResult<Option<User>> {
+	let Some(cookie) = jar.get(SESSION_COOKIE) else {
+		return Ok(None);
+	};
+	let row: Option<(String, String)> = sessions::table
+		.inner_join(users::table)
+		.filter(sessions::token_hash.eq(hash_token(cookie.value())))
+		.filter(sessions::expires_at.gt(unix_time(SystemTime::now())))
+		.select((users::name, users::role))
+		.first(&mut db.get()?)
+		.optional()?;
+
+	let Some((name, role)) = row else {
+		return Ok(None);
+	};
+	let role = Role::parse(&role).ok_or_else(|| format!("Invalid role of user {}: {}", name, role))?;
+	return Ok(Some(User { name, role }));
+}
+
+/// 注销请求所带的会话并删除 Cookie，没有会话时什么也不做。
+pub fn remove_session(db: &DbPool, jar: CookieJar) -> DbResult<CookieJar> {
+	let Some(cookie) = jar.get(SESSION_COOKIE) else {
+		return Ok(jar);
+	};
+	let current = sessions::table.filter(sessions::token_hash.eq(hash_token(cookie.value())));
+	diesel::delete(current).execute(&mut db.get()?)?;
+	return Ok(jar.remove(Cookie::build(SESSION_COOKIE, "").path("/").finish()));
+}
+
+#[derive(Queryable, Serialize)]
+pub struct SessionVO {
+	id: String,
+	username: String,
+	user_agent: Option<String>,
+	created_at: i64,
+	expires_at: i64,
+
+	/// 是否是发出这个请求的会话。
+	current: bool,
+}
+
+/// 列出未过期的会话，最新的在前。
+pub async fn list_sessions(State(ctx): State<OSSContext>, jar: CookieJar) -> Response {
+	let current = jar.get(SESSION_COOKIE).map(|c| hash_token(c.value())).unwrap_or_default();
+
+	let list = ctx.db.get().map_err(Into::into).and_then(|mut conn| -> DbResult<Vec<SessionVO>> {
+		Ok(sessions::table
+			.filter(sessions::expires_at.gt(unix_time(SystemTime::now())))
+			.select((
+				sessions::id,
+				sessions::username,
+				sessions::user_agent,
+				sessions::created_at,
+				sessions::expires_at,
+				sessions::token_hash.eq(current),
+			))
+			.order(sessions::created_at.desc())
+			.load(&mut conn)?)
+	});
+
+	return match list {
+		Ok(list) => Json(list).into_response(),
+		Err(e) => {
+			log::error!("Failed to list sessions: {}", e);
+			StatusCode::INTERNAL_SERVER_ERROR.into_response()
+		}
+	};
+}
+
+pub async fn revoke_session(State(ctx): State<OSSContext>, Path(id): Path<String>) -> Response {
+	let deleted = ctx.db.get().map_err(Into::into).and_then(|mut conn| -> DbResult<usize> {
+		Ok(diesel::delete(sessions::table.find(&id)).execute(&mut conn)?)
+	});
+
+	return match deleted {
+		Ok(0) => StatusCode::NOT_FOUND.into_response(),
+		Ok(_) => {
+			log::info!("Revoked session {}", id);
+			StatusCode::NO_CONTENT.into_response()
+		}
+		Err(e) => {
+			log::error!("Failed to revoke session: {}", e);
+			StatusCode::INTERNAL_SERVER_ERROR.into_response()
+		}
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use axum::extract::{Path, State};
+	use axum::http::{HeaderMap, StatusCode};
+	use axum::http::header::{COOKIE, SET_COOKIE};
+	use axum::response::IntoResponse;
+	use axum_extra::extract::CookieJar;
+	use diesel::prelude::*;
+
+	use crate::api::logout;
+	use crate::context::{OSSContext, test_context};
+	use crate::schema::sessions;
+	use crate::session::{check_session, create_session, revoke_session, SESSION_COOKIE, SessionOptions};
+	use crate::user::bootstrap_admin;
+
+	/// 登录并返回之后的请求所带的 Cookie。
+	fn login(ctx: &OSSContext) -> CookieJar {
+		let jar = create_session(ctx, CookieJar::new(), "admin", Some("test")).unwrap();
+		let cookie = jar.get(SESSION_COOKIE).unwrap().stripped().to_string();
+
+		let mut headers = HeaderMap::new();
+		headers.insert(COOKIE, cookie.parse().unwrap());
+		return CookieJar::from_headers(&headers);
+	}
+
+	fn setup() -> (tempfile::TempDir, OSSContext) {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = test_context(dir.path());
+		bootstrap_admin(&ctx.db, "pw").unwrap();
+		return (dir, ctx);
+	}
+
+	#[test]
+	fn session_user() {
+		let (_dir, ctx) = setup();
+		let jar = login(&ctx);
+		assert_eq!(check_session(&ctx.db, &jar).unwrap().unwrap().name, "admin");
+		assert!(check_session(&ctx.db, &CookieJar::new()).unwrap().is_none());
+	}
+
+	#[test]
+	fn expired() {
+		let (_dir, mut ctx) = setup();
+		ctx.session = Arc::new(SessionOptions { lifetime: 0, secure_cookie: true });
+		let jar = login(&ctx);
+		assert!(check_session(&ctx.db, &jar).unwrap().is_none());
+	}
+
+	#[tokio::test]
+	async fn revoke() {
+		let (_dir, ctx) = setup();
+		let jar = login(&ctx);
+		let id: String = sessions::table.select(sessions::id).first(&mut ctx.db.get().unwrap()).unwrap();
+
+		let response = revoke_session(State(ctx.clone()), Path(id.clone())).await;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		assert!(check_session(&ctx.db, &jar).unwrap().is_none());
+
+		let response = revoke_session(State(ctx.clone()), Path(id)).await;
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+	}
+
+	#[tokio::test]
+	async fn logout_invalidates_cookie() {
+		let (_dir, ctx) = setup();
+		let jar = login(&ctx);
+		let other = login(&ctx);
+
+		let response = logout(State(ctx.clone()), jar.clone()).await.into_response();
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		let cookie = response.headers()[SET_COOKIE].to_str().unwrap();
+		assert!(cookie.starts_with("session=;"));
+
+		// 即使客户端没有删除 Cookie，它也不再有效，而其它会话不受影响。
+		assert!(check_session(&ctx.db, &jar).unwrap().is_none());
+		assert!(check_session(&ctx.db, &other).unwrap().is_some());
+	}
+}
@@ -1,14 +1,22 @@
+use std::io;
 use std::io::ErrorKind;
 use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 
 use axum::body::Body;
 use axum::extract::State;
-use axum::http::{Method, Request, StatusCode};
+use axum::http::{HeaderValue, Method, Request, StatusCode};
+use axum::http::header::{CONTENT_ENCODING, VARY};
 use axum::response::{IntoResponse, Response};
 use axum::Router;
 
+use crate::compress::CompressOptions;
+use crate::negotiate::preferred_encodings;
 use crate::range::{FileCache, FileRangeReadr, send_range};
 
+/// 预压缩文件的编码和扩展名，顺序即为权重相同时的偏好。
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
 fn normalize_path(base: &Path, path: &str) -> Option<PathBuf> {
 	let path = path.trim_start_matches('/');
 	let path = Path::new(path);
@@ -17,17 +25,11 @@ fn normalize_path(base: &Path, path: &str) -> Option<PathBuf> {
 
 	for component in path.components() {
 		match component {
-			Component::Normal(comp) => {
-				// protect against paths like `/foo/c:/bar/baz` (#204)
-
-				if Path::new(&comp)
-					.components()
-					.all(|c| matches!(c, Component::Normal(_)))
-				{
-					joined.push(comp)
-				} else {
-					return None;
-				}
+			// protect against paths like `/foo/c:/bar/baz` (#204)
+			Component::Normal(comp) if Path::new(&comp)
+				.components()
+				.all(|c| matches!(c, Component::Normal(_))) => {
+				joined.push(comp)
 			}
 			Component::CurDir => {}
 			_ => return None,
@@ -40,6 +42,7 @@ fn normalize_path(base: &Path, path: &str) -> Option<PathBuf> {
 struct ServeDirectory {
 	pub base: PathBuf,
 	pub fallback: Option<PathBuf>,
+	pub compress: Arc<CompressOptions>,
 }
 
 async fn serve_dir(state: State<ServeDirectory>, request: Request<Body>) -> Response {
@@ -50,13 +53,13 @@ async fn serve_dir(state: State<ServeDirectory>, request: Request<Body>) -> Resp
 
 	let path = request.uri().path();
 	if let Some(path) = normalize_path(&state.base, path) {
-		let response = serve_file(&path, &request).await;
+		let response = serve_file(&path, &request, &state.compress).await;
 
 		if response.status() != StatusCode::NOT_FOUND {
 			return response;
 		}
 		if let Some(default) = &state.fallback {
-			return serve_file(default, &request).await;
+			return serve_file(default, &request, &state.compress).await;
 		}
 		response
 	} else {
@@ -64,17 +67,22 @@ async fn serve_dir(state: State<ServeDirectory>, request: Request<Body>) -> Resp
 	}
 }
 
-async fn serve_file(path: &Path, request: &Request<Body>) -> Response {
-	let mime = mime_guess::from_path(&path)
+async fn serve_file(path: &Path, request: &Request<Body>, compress: &Arc<CompressOptions>) -> Response {
+	let mime = mime_guess::from_path(path)
 		.first_raw()
 		.unwrap_or("application/octet-stream")
 		.to_string();
 
-	// TODO: compressed variants
-
-	match FileRangeReadr::open(path, mime, FileCache::Modified).await {
-		Ok(file) => {
-			send_range(request.headers(), file).await
+	let mut response = match open_variant(path, request, mime).await {
+		Ok((mut file, encoding)) => {
+			if encoding.is_none() {
+				file.compress = Some(compress.clone());
+			}
+			let mut response = send_range(request.headers(), file).await;
+			if let Some(encoding) = encoding {
+				response.headers_mut().insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+			}
+			response
 		},
 		Err(e) => match e.kind() {
 			ErrorKind::NotFound => {
@@ -85,9 +93,107 @@ async fn serve_file(path: &Path, request: &Request<Body>) -> Response {
 			},
 			_ => StatusCode::INTERNAL_SERVER_ERROR.into_response()
 		}
+	};
+
+	// 动态压缩时 send_range 已经添加了 Vary。
+	if response.status() != StatusCode::NOT_FOUND && !response.headers().contains_key(VARY) {
+		response.headers_mut().append(VARY, HeaderValue::from_static("Accept-Encoding"));
 	}
+	response
+}
+
+/// 按 Accept-Encoding 依次尝试打开预压缩的文件（如 `app.js.br`），都不存在则打开原文件。
+/// MIME 总是由原文件名决定，压缩只体现在 Content-Encoding 上。
+async fn open_variant(
+	path: &Path,
+	request: &Request<Body>,
+	mime: String,
+) -> io::Result<(FileRangeReadr, Option<&'static str>)> {
+	let available = PRECOMPRESSED.map(|(encoding, _)| encoding);
+
+	for encoding in preferred_encodings(request.headers(), &available) {
+		let (_, extension) = PRECOMPRESSED.iter().find(|(e, _)| *e == encoding).unwrap();
+
+		let mut compressed = path.as_os_str().to_owned();
+		compressed.push(".");
+		compressed.push(extension);
+
+		match FileRangeReadr::open(compressed, mime.clone(), FileCache::Encoded(encoding)).await {
+			Ok(file) => return Ok((file, Some(encoding))),
+			Err(e) if e.kind() == ErrorKind::NotFound => continue,
+			Err(e) => return Err(e),
+		}
+	}
+
+	let file = FileRangeReadr::open(path, mime, FileCache::Modified).await?;
+	return Ok((file, None));
+}
+
+pub fn serve_static<OS>(
+	base: PathBuf,
+	fallback: Option<PathBuf>,
+	compress: Arc<CompressOptions>,
+) -> Router<OS> {
+	Router::new().fallback(serve_dir).with_state(ServeDirectory { base, fallback, compress })
 }
 
-pub fn serve_static<OS>(base: PathBuf, fallback: Option<PathBuf>) -> Router<OS> {
-	Router::new().fallback(serve_dir).with_state(ServeDirectory { base, fallback })
+#[cfg(test)]
+mod tests {
+	use std::path::Path;
+	use std::sync::Arc;
+
+	use axum::body::Body;
+	use axum::http::{HeaderMap, Request, StatusCode};
+	use axum::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, VARY};
+	use hyper::body::to_bytes;
+
+	use crate::compress::CompressOptions;
+	use crate::static_files::serve_file;
+
+	const FILE: &str = "test-files/sendrange.txt";
+
+	/// 请求测试文件，返回响应头和响应体。
+	async fn get(accept_encoding: Option<&str>) -> (HeaderMap, Vec<u8>) {
+		let mut request = Request::get("/sendrange.txt");
+		if let Some(value) = accept_encoding {
+			request = request.header(ACCEPT_ENCODING, value);
+		}
+		let request = request.body(Body::empty()).unwrap();
+		let compress = Arc::new(CompressOptions::default());
+
+		let response = serve_file(Path::new(FILE), &request, &compress).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		let (parts, body) = response.into_parts();
+		return (parts.headers, to_bytes(body).await.unwrap().to_vec());
+	}
+
+	#[tokio::test]
+	async fn precompressed_by_quality() {
+		let (headers, body) = get(Some("gzip;q=0.5, br")).await;
+		assert_eq!(headers[CONTENT_ENCODING], "br");
+		assert_eq!(body, std::fs::read("test-files/sendrange.txt.br").unwrap());
+
+		let (headers, body) = get(Some("gzip, br;q=0.5")).await;
+		assert_eq!(headers[CONTENT_ENCODING], "gzip");
+		assert_eq!(body, std::fs::read("test-files/sendrange.txt.gz").unwrap());
+
+		// 类型由原文件名决定，不是 .gz 的类型。
+		assert_eq!(headers[CONTENT_TYPE], "text/plain");
+		assert_eq!(headers[VARY], "Accept-Encoding");
+	}
+
+	#[tokio::test]
+	async fn equal_quality() {
+		let (headers, _) = get(Some("gzip, br")).await;
+		assert_eq!(headers[CONTENT_ENCODING], "br");
+	}
+
+	#[tokio::test]
+	async fn identity_fallback() {
+		let (headers, body) = get(None).await;
+		assert!(!headers.contains_key(CONTENT_ENCODING));
+		assert_eq!(headers[CONTENT_TYPE], "text/plain");
+		assert_eq!(headers[VARY], "Accept-Encoding");
+		assert_eq!(body, std::fs::read(FILE).unwrap());
+	}
 }
@@ -0,0 +1,269 @@
+use std::time::SystemTime;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use diesel::prelude::*;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::context::OSSContext;
+use crate::db::{DbPool, DbResult, unix_time};
+use crate::schema::api_tokens;
+use crate::sigv4::hex;
+
+/*
+ * API 令牌，给 CI 和编辑器等程序使用，通过 `Authorization: Bearer <token>` 传递。
+ * 每个令牌有自己的权限、可选的存储桶限制和过期时间，可以单独吊销，不必共享管理密码。
+ *
+ * 令牌是足够长的随机字符串，所以只保存一次 SHA-256 即可，不需要慢 Hash。
+ */
+
+const TOKEN_PREFIX: &str = "lwoss_";
+
+/// 令牌的权限，admin 包含其它所有权限。
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+	/// 下载私有存储桶的对象，以及列出对象。
+	Read,
+	/// 上传对象和变体，包括断点续传。
+	Upload,
+	/// 删除对象。
+	Delete,
+	/// 管理接口，比如签发预签名 URL 和管理令牌。
+	Admin,
+}
+
+impl Scope {
+
+	fn name(self) -> &'static str {
+		match self {
+			Scope::Read => "read",
+			Scope::Upload => "upload",
+			Scope::Delete => "delete",
+			Scope::Admin => "admin",
+		}
+	}
+
+	fn parse(name: &str) -> Option<Self> {
+		match name {
+			"read" => Some(Scope::Read),
+			"upload" => Some(Scope::Upload),
+			"delete" => Some(Scope::Delete),
+			"admin" => Some(Scope::Admin),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = api_tokens)]
+struct ApiToken {
+	name: String,
+	scopes: String,
+	bucket: Option<String>,
+	expires_at: Option<i64>,
+}
+
+impl ApiToken {
+
+	fn allows(&self, scope: Scope, bucket: Option<&str>, now: i64) -> bool {
+		if self.expires_at.is_some_and(|t| t <= now) {
+			return false;
+		}
+		// 限定了存储桶的令牌不能访问全局的管理接口。
+		if self.bucket.is_some() && self.bucket.as_deref() != bucket {
+			return false;
+		}
+		return self.scopes.split(',')
+			.filter_map(Scope::parse)
+			.any(|s| s == scope || s == Scope::Admin);
+	}
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TokenError {
+	/// 令牌不存在或已被吊销。
+	Invalid,
+	/// 令牌有效，但没有所需的权限，或者已经过期。
+	Forbidden,
+}
+
+/// 令牌和会话都是高熵的随机字符串，只保存它们的 SHA-256。
+pub fn hash_token(token: &str) -> String {
+	return hex(&Sha256::digest(token.as_bytes()));
+}
+
+/// 检查令牌能否以 `scope` 权限访问 `bucket`，bucket 为 None 表示全局的管理接口，成功时返回令牌的名字。
+pub fn check_token(db: &DbPool, token: &str, scope: Scope, bucket: Option<&str>) -> DbResult<Result<String, TokenError>> {
+	let mut conn = db.get()?;
+	let row = api_tokens::table
+		.filter(api_tokens::token_hash.eq(hash_token(token)))
+		.select(ApiToken::as_select())
+		.first(&mut conn)
+		.optional()?;
+
+	return Ok(match row {
+		None => Err(TokenError::Invalid),
+		Some(row) if row.allows(scope, bucket, unix_time(SystemTime::now())) => Ok(row.name),
+		Some(_) => Err(TokenError::Forbidden),
+	});
+}
+
+#[derive(Deserialize)]
+pub struct CreateTokenRequest {
+	name: String,
+	scopes: Vec<Scope>,
+	bucket: Option<String>,
+
+	/// 有效期（秒），不设置则永不过期。
+	expires_in: Option<u64>,
+}
+
+#[derive(Queryable, Selectable, Serialize)]
+#[diesel(table_name = api_tokens)]
+pub struct TokenVO {
+	id: String,
+	name: String,
+	#[serde(serialize_with = "serialize_scopes")]
+	scopes: String,
+	bucket: Option<String>,
+	expires_at: Option<i64>,
+	created_at: i64,
+}
+
+fn serialize_scopes<S: serde::Serializer>(scopes: &str, serializer: S) -> Result<S::Ok, S::Error> {
+	return serializer.collect_seq(scopes.split(',').filter(|s| !s.is_empty()));
+}
+
+#[derive(Serialize)]
+pub struct CreatedTokenVO {
+	#[serde(flatten)]
+	info: TokenVO,
+
+	/// 令牌的明文，只在创建时返回这一次。
+	token: String,
+}
+
+pub async fn create_token(State(ctx): State<OSSContext>, Json(body): Json<CreateTokenRequest>) -> Response {
+	if body.name.is_empty() || body.scopes.is_empty() {
+		return (StatusCode::BAD_REQUEST, "Name and scopes are required").into_response();
+	}
+
+	let mut rng = rand::thread_rng();
+	let token = format!("{}{}", TOKEN_PREFIX, Alphanumeric.sample_string(&mut rng, 40));
+	let now = unix_time(SystemTime::now());
+
+	let mut scopes: Vec<_> = body.scopes.iter().map(|s| s.name()).collect();
+	scopes.sort();
+	scopes.dedup();
+
+	let info = TokenVO {
+		id: Alphanumeric.sample_string(&mut rng, 16),
+		name: body.name,
+		scopes: scopes.join(","),
+		bucket: body.bucket.filter(|b| !b.is_empty()),
+		expires_at: body.expires_in.map(|secs| now + secs as i64),
+		created_at: now,
+	};
+
+	let inserted = ctx.db.get().map_err(Into::into).and_then(|mut conn| -> DbResult<usize> {
+		let values = (
+			api_tokens::id.eq(&info.id),
+			api_tokens::token_hash.eq(hash_token(&token)),
+			api_tokens::name.eq(&info.name),
+			api_tokens::scopes.eq(&info.scopes),
+			api_tokens::bucket.eq(&info.bucket),
+			api_tokens::expires_at.eq(info.expires_at),
+			api_tokens::created_at.eq(info.created_at),
+		);
+		Ok(diesel::insert_into(api_tokens::table).values(values).execute(&mut conn)?)
+	});
+
+	return match inserted {
+		Ok(_) => {
+			log::info!("Created API token {} ({})", info.id, info.name);
+			(StatusCode::CREATED, Json(CreatedTokenVO { info, token })).into_response()
+		}
+		Err(e) => {
+			log::error!("Failed to create API token: {}", e);
+			StatusCode::INTERNAL_SERVER_ERROR.into_response()
+		}
+	};
+}
+
+pub async fn list_tokens(State(ctx): State<OSSContext>) -> Response {
+	let list = ctx.db.get().map_err(Into::into).and_then(|mut conn| -> DbResult<Vec<TokenVO>> {
+		Ok(api_tokens::table
+			.select(TokenVO::as_select())
+			.order(api_tokens::created_at.desc())
+			.load(&mut conn)?)
+	});
+
+	return match list {
+		Ok(list) => Json(list).into_response(),
+		Err(e) => {
+			log::error!("Failed to list API tokens: {}", e);
+			StatusCode::INTERNAL_SERVER_ERROR.into_response()
+		}
+	};
+}
+
+/// 吊销令牌，直接删除记录，之后使用它的请求都会返回 401。
+pub async fn revoke_token(State(ctx): State<OSSContext>, Path(id): Path<String>) -> Response {
+	let deleted = ctx.db.get().map_err(Into::into).and_then(|mut conn| -> DbResult<usize> {
+		Ok(diesel::delete(api_tokens::table.find(&id)).execute(&mut conn)?)
+	});
+
+	return match deleted {
+		Ok(0) => StatusCode::NOT_FOUND.into_response(),
+		Ok(_) => {
+			log::info!("Revoked API token {}", id);
+			StatusCode::NO_CONTENT.into_response()
+		}
+		Err(e) => {
+			log::error!("Failed to revoke API token: {}", e);
+			StatusCode::INTERNAL_SERVER_ERROR.into_response()
+		}
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::token::{ApiToken, Scope};
+
+	fn token(scopes: &str, bucket: Option<&str>, expires_at: Option<i64>) -> ApiToken {
+		return ApiToken { name: "test".into(), scopes: scopes.into(), bucket: bucket.map(str::to_owned), expires_at };
+	}
+
+	#[test]
+	fn scopes() {
+		let t = token("read,upload", None, None);
+		assert!(t.allows(Scope::Read, Some("image"), 0));
+		assert!(t.allows(Scope::Upload, Some("image"), 0));
+		assert!(!t.allows(Scope::Delete, Some("image"), 0));
+		assert!(!t.allows(Scope::Admin, None, 0));
+
+		let admin = token("admin", None, None);
+		assert!(admin.allows(Scope::Delete, Some("image"), 0));
+		assert!(admin.allows(Scope::Admin, None, 0));
+	}
+
+	#[test]
+	fn bucket_restricted() {
+		let t = token("admin", Some("image"), None);
+		assert!(t.allows(Scope::Delete, Some("image"), 0));
+		assert!(!t.allows(Scope::Delete, Some("video"), 0));
+		assert!(!t.allows(Scope::Admin, None, 0));
+	}
+
+	#[test]
+	fn expired() {
+		let t = token("read", None, Some(100));
+		assert!(t.allows(Scope::Read, None, 99));
+		assert!(!t.allows(Scope::Read, None, 100));
+	}
+}
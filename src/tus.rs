@@ -0,0 +1,586 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{Extension, Router};
+use axum::extract::{BodyStream, OriginalUri, Path, State};
+use axum::http::{HeaderMap, HeaderValue, Method, Request, StatusCode};
+use axum::http::header::{ACCESS_CONTROL_REQUEST_METHOD, CACHE_CONTROL, CONTENT_TYPE, LOCATION};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{head, post};
+use base64::{Engine as _, engine::general_purpose};
+use diesel::prelude::*;
+use futures::future::Either;
+use futures::StreamExt;
+use httpdate::fmt_http_date;
+use rand::distributions::{Alphanumeric, DistString};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tower::{Layer, Service};
+use tower_http::cors::{Cors, CorsLayer};
+
+use crate::api::Identity;
+use crate::context::{OSSContext, ReceiveOptions, UploadMeta};
+use crate::db::{DbResult, unix_time};
+use crate::manual::{ManualBucket, save_error};
+use crate::schema::uploads;
+
+/*
+ * tus 1.0 断点续传协议，支持 creation、termination 和 expiration 扩展。
+ * https://tus.io/protocols/resumable-upload
+ *
+ * 客户端先 POST 创建上传，然后用 PATCH 分多次追加内容，断线后用 HEAD 查询偏移再继续。
+ * 收完最后一块后，整个文件会像普通上传一样检查类型、计算 Hash 并保存，
+ * 所以最终的对象与直接上传的没有区别，Hash 在响应的 Object-Hash 头里。
+ *
+ * 已接收的部分保存在 buf_dir/tus 目录，文件的大小就是偏移，数据库只记录总长度等信息。
+ */
+
+const TUS_VERSION: &str = "1.0.0";
+
+const EXTENSIONS: &str = "creation,termination,expiration";
+
+const OFFSET_OCTET_STREAM: &str = "application/offset+octet-stream";
+
+/// 上传完成后，对象的 Hash 放在这个头里，因为协议规定 PATCH 的响应是 204 没有内容。
+pub const OBJECT_HASH: &str = "object-hash";
+
+/// 跨域时需要暴露给前端的头部。
+pub const EXPOSE_HEADERS: [&str; 7] = [
+	"location", "tus-resumable", "tus-version", "tus-max-size",
+	"upload-offset", "upload-length", OBJECT_HASH,
+];
+
+#[derive(Queryable, Insertable)]
+#[diesel(table_name = uploads)]
+struct Upload {
+	id: String,
+	bucket: String,
+	length: i64,
+
+	/// 原样保存的 Upload-Metadata 头，HEAD 请求需要返回它。
+	metadata: Option<String>,
+
+	/// Unix 时间戳，单位秒。
+	expires_at: i64,
+}
+
+/// 正在处理 PATCH、DELETE 或过期清理的上传，同一个上传不能同时追加，否则偏移会错乱。
+#[derive(Clone, Default)]
+pub struct UploadLocks(Arc<Mutex<HashSet<String>>>);
+
+struct LockGuard {
+	locks: UploadLocks,
+	id: String,
+}
+
+impl UploadLocks {
+	fn acquire(&self, id: &str) -> Option<LockGuard> {
+		let mut set = self.0.lock().unwrap();
+		if !set.insert(id.to_owned()) {
+			return None;
+		}
+		return Some(LockGuard { locks: self.clone(), id: id.to_owned() });
+	}
+}
+
+impl Drop for LockGuard {
+	fn drop(&mut self) {
+		self.locks.0.lock().unwrap().remove(&self.id);
+	}
+}
+
+pub fn tus_routes() -> Router<ManualBucket> {
+	return Router::new()
+		.route("/tus", post(create).options(options))
+		.route("/tus/:id", head(status).patch(append).delete(terminate));
+}
+
+/// tower-http 的 CorsLayer 把所有 OPTIONS 请求都当作预检，但 tus 用它来查询服务端支持的功能，
+/// 所以没有 Access-Control-Request-Method 的 OPTIONS 请求跳过 CORS，直接交给内层的服务。
+#[derive(Clone)]
+pub struct TusCorsLayer(pub CorsLayer);
+
+impl<S> Layer<S> for TusCorsLayer {
+	type Service = TusCors<S>;
+
+	fn layer(&self, inner: S) -> Self::Service {
+		return TusCors(self.0.layer(inner));
+	}
+}
+
+#[derive(Clone)]
+pub struct TusCors<S>(Cors<S>);
+
+impl<S, B> Service<Request<B>> for TusCors<S>
+where
+	S: Service<Request<B>>,
+	Cors<S>: Service<Request<B>, Response = S::Response, Error = S::Error>,
+{
+	type Response = S::Response;
+	type Error = S::Error;
+	type Future = Either<S::Future, <Cors<S> as Service<Request<B>>>::Future>;
+
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		return self.0.poll_ready(cx);
+	}
+
+	fn call(&mut self, request: Request<B>) -> Self::Future {
+		if request.method() == Method::OPTIONS && !request.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD) {
+			return Either::Left(self.0.get_mut().call(request));
+		}
+		return Either::Right(self.0.call(request));
+	}
+}
+
+fn part_path(ctx: &OSSContext, id: &str) -> PathBuf {
+	return ctx.buf_dir.join("tus").join(id);
+}
+
+/// 所有 tus 的响应都要带上 Tus-Resumable。
+fn tus_response(status: StatusCode) -> Response {
+	let mut response = status.into_response();
+	response.headers_mut().insert("tus-resumable", HeaderValue::from_static(TUS_VERSION));
+	return response;
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+	return headers.get(name)?.to_str().ok()?.parse().ok();
+}
+
+fn expires_header(expires_at: i64) -> HeaderValue {
+	let time = UNIX_EPOCH + Duration::from_secs(expires_at as u64);
+	return HeaderValue::from_str(&fmt_http_date(time)).unwrap();
+}
+
+/// 检查客户端的协议版本，除了 OPTIONS 之外的请求都要有，不支持时返回 412 响应。
+fn version_mismatch(headers: &HeaderMap) -> Option<Response> {
+	if headers.get("tus-resumable").is_some_and(|v| v == TUS_VERSION) {
+		return None;
+	}
+	let mut response = tus_response(StatusCode::PRECONDITION_FAILED);
+	response.headers_mut().insert("tus-version", HeaderValue::from_static(TUS_VERSION));
+	return Some(response);
+}
+
+/// 解析 Upload-Metadata，格式为逗号分隔的 `key base64(value)`，值可以省略。
+fn parse_metadata(value: &str) -> Option<HashMap<String, String>> {
+	let mut map = HashMap::new();
+	for pair in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+		let (key, value) = match pair.split_once(' ') {
+			Some((key, value)) => (key, general_purpose::STANDARD.decode(value.trim()).ok()?),
+			None => (pair, Vec::new()),
+		};
+		map.insert(key.to_owned(), String::from_utf8_lossy(&value).into_owned());
+	}
+	return Some(map);
+}
+
+async fn options(state: State<ManualBucket>) -> Response {
+	let mut response = tus_response(StatusCode::NO_CONTENT);
+	let headers = response.headers_mut();
+	headers.insert("tus-version", HeaderValue::from_static(TUS_VERSION));
+	headers.insert("tus-extension", HeaderValue::from_static(EXTENSIONS));
+	if let Some(max) = state.config.max_size {
+		headers.insert("tus-max-size", max.into());
+	}
+	return response;
+}
+
+async fn create(state: State<ManualBucket>, OriginalUri(uri): OriginalUri, headers: HeaderMap) -> Response {
+	if let Some(response) = version_mismatch(&headers) {
+		return response;
+	}
+
+	// 不支持 creation-defer-length 扩展，必须一开始就给出长度。
+	let length = match header_u64(&headers, "upload-length") {
+		Some(length) => length,
+		None => return tus_response(StatusCode::BAD_REQUEST),
+	};
+	if state.config.max_size.is_some_and(|max| length > max) {
+		return tus_response(StatusCode::PAYLOAD_TOO_LARGE);
+	}
+
+	let metadata = headers.get("upload-metadata").and_then(|v| v.to_str().ok());
+	if metadata.is_some_and(|v| parse_metadata(v).is_none()) {
+		return tus_response(StatusCode::BAD_REQUEST);
+	}
+
+	let upload = Upload {
+		id: Alphanumeric.sample_string(&mut rand::thread_rng(), 32),
+		bucket: state.config.name.clone(),
+		length: length as i64,
+		metadata: metadata.map(str::to_owned),
+		expires_at: unix_time(SystemTime::now()) + state.config.tus_expiration as i64,
+	};
+
+	let path = part_path(&state.ctx, &upload.id);
+	let created = async {
+		tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+		tokio::fs::File::create(&path).await?;
+
+		let mut conn = state.ctx.db.get()?;
+		diesel::insert_into(uploads::table).values(&upload).execute(&mut conn)?;
+		DbResult::Ok(())
+	};
+	if let Err(e) = created.await {
+		log::error!("Failed to create upload: {}", e);
+		return tus_response(StatusCode::INTERNAL_SERVER_ERROR);
+	}
+
+	let location = format!("{}/{}", uri.path().trim_end_matches('/'), upload.id);
+	let mut response = tus_response(StatusCode::CREATED);
+	response.headers_mut().insert(LOCATION, HeaderValue::from_str(&location).unwrap());
+	response.headers_mut().insert("upload-expires", expires_header(upload.expires_at));
+	return response;
+}
+
+impl ManualBucket {
+
+	/// 查找本存储桶中未过期的上传，过期的记录稍后由 `expire_uploads` 清理。
+	fn find_upload(&self, id: &str) -> DbResult<Option<Upload>> {
+		let mut conn = self.ctx.db.get()?;
+		let upload = uploads::table
+			.find(id)
+			.filter(uploads::bucket.eq(&self.config.name))
+			.filter(uploads::expires_at.gt(unix_time(SystemTime::now())))
+			.first::<Upload>(&mut conn)
+			.optional()?;
+		return Ok(upload);
+	}
+
+	fn delete_record(&self, id: &str) -> DbResult<()> {
+		let mut conn = self.ctx.db.get()?;
+		diesel::delete(uploads::table.find(id)).execute(&mut conn)?;
+		return Ok(());
+	}
+
+	fn remove_upload(&self, id: &str) -> DbResult<()> {
+		self.delete_record(id)?;
+		match std::fs::remove_file(part_path(&self.ctx, id)) {
+			Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+			_ => Ok(()),
+		}
+	}
+}
+
+/// 把请求体追加到分片文件，返回 false 表示超出了声明的长度，已写入的部分仍然保留。
+async fn write_body(file: &mut File, body: &mut BodyStream, current: &mut u64, length: u64) -> io::Result<bool> {
+	while let Some(chunk) = body.next().await {
+		let data = match chunk {
+			Ok(data) => data,
+			Err(_) => break,
+		};
+		if *current + data.len() as u64 > length {
+			file.flush().await?;
+			return Ok(false);
+		}
+		file.write_all(&data).await?;
+		*current += data.len() as u64;
+	}
+	file.flush().await?;
+	return Ok(true);
+}
+
+/// 查找上传并获取已接收的长度，出错时直接返回响应。
+async fn load(state: &ManualBucket, id: &str) -> Result<(Upload, u64), Response> {
+	let upload = match state.find_upload(id) {
+		Ok(Some(upload)) => upload,
+		Ok(None) => return Err(tus_response(StatusCode::NOT_FOUND)),
+		Err(e) => {
+			log::error!("Failed to load upload {}: {}", id, e);
+			return Err(tus_response(StatusCode::INTERNAL_SERVER_ERROR));
+		}
+	};
+	return match tokio::fs::metadata(part_path(&state.ctx, id)).await {
+		Ok(metadata) => Ok((upload, metadata.len())),
+		Err(e) if e.kind() == ErrorKind::NotFound => Err(tus_response(StatusCode::NOT_FOUND)),
+		Err(_) => Err(tus_response(StatusCode::INTERNAL_SERVER_ERROR)),
+	};
+}
+
+async fn status(state: State<ManualBucket>, Path(id): Path<String>, headers: HeaderMap) -> Response {
+	if let Some(response) = version_mismatch(&headers) {
+		return response;
+	}
+	let (upload, offset) = match load(&state, &id).await {
+		Ok(loaded) => loaded,
+		Err(response) => return response,
+	};
+
+	let mut response = tus_response(StatusCode::OK);
+	let headers = response.headers_mut();
+	headers.insert("upload-offset", offset.into());
+	headers.insert("upload-length", upload.length.into());
+	headers.insert("upload-expires", expires_header(upload.expires_at));
+	headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+	if let Some(metadata) = upload.metadata.and_then(|v| HeaderValue::from_str(&v).ok()) {
+		headers.insert("upload-metadata", metadata);
+	}
+	return response;
+}
+
+async fn append(
+	state: State<ManualBucket>,
+	Path(id): Path<String>,
+	identity: Option<Extension<Identity>>,
+	headers: HeaderMap,
+	mut body: BodyStream,
+) -> Response {
+	if let Some(response) = version_mismatch(&headers) {
+		return response;
+	}
+	if headers.get(CONTENT_TYPE).is_none_or(|v| v != OFFSET_OCTET_STREAM) {
+		return tus_response(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+	}
+	let offset = match header_u64(&headers, "upload-offset") {
+		Some(offset) => offset,
+		None => return tus_response(StatusCode::BAD_REQUEST),
+	};
+
+	let _guard = match state.ctx.upload_locks.acquire(&id) {
+		Some(guard) => guard,
+		None => return tus_response(StatusCode::CONFLICT),
+	};
+	let (upload, mut current) = match load(&state, &id).await {
+		Ok(loaded) => loaded,
+		Err(response) => return response,
+	};
+	if offset != current {
+		return tus_response(StatusCode::CONFLICT);
+	}
+
+	// 断线时已经收到的部分也要保存，这正是断点续传的意义。
+	let path = part_path(&state.ctx, &id);
+	let length = upload.length as u64;
+	let mut file = match OpenOptions::new().append(true).open(&path).await {
+		Ok(file) => file,
+		Err(e) if e.kind() == ErrorKind::NotFound => return tus_response(StatusCode::NOT_FOUND),
+		Err(e) => {
+			log::error!("Failed to open upload {}: {}", id, e);
+			return tus_response(StatusCode::INTERNAL_SERVER_ERROR);
+		}
+	};
+
+	match write_body(&mut file, &mut body, &mut current, length).await {
+		Ok(true) => drop(file),
+		Ok(false) => return tus_response(StatusCode::BAD_REQUEST),
+		Err(e) => {
+			log::error!("Failed to write upload {}: {}", id, e);
+			return tus_response(StatusCode::INTERNAL_SERVER_ERROR);
+		}
+	}
+
+	let mut response = tus_response(StatusCode::NO_CONTENT);
+	response.headers_mut().insert("upload-offset", current.into());
+	response.headers_mut().insert("upload-expires", expires_header(upload.expires_at));
+
+	if current == length {
+		let uploader = identity.map(|Extension(Identity(name))| name);
+		match finish(&state, upload, path, uploader).await {
+			Ok(hash) => {
+				response.headers_mut().insert(OBJECT_HASH, HeaderValue::from_str(&hash).unwrap());
+			}
+			Err(response) => return response,
+		}
+	}
+	return response;
+}
+
+/// 接收完成，检查并保存文件，无论成功与否上传都会被删除。
+/// 分片文件交给 FileBuf 管理，保存时被移走，失败则随它一起删除。
+///
+/// 上传者记为发送最后一块的身份，通常与创建者相同。
+async fn finish(state: &ManualBucket, upload: Upload, path: PathBuf, uploader: Option<String>) -> Result<String, Response> {
+	let metadata = upload.metadata.as_deref().and_then(parse_metadata).unwrap_or_default();
+
+	let options = ReceiveOptions {
+		claimed_type: metadata.get("filetype").map(String::as_str),
+		allowed_types: &state.config.allowed_types,
+		hash: &state.config.hash,
+		digests: Vec::new(),
+		max_size: state.config.max_size,
+		md5: false,
+	};
+	let inspected = state.ctx.inspect_file(path, options).await;
+
+	if let Err(e) = state.delete_record(&upload.id) {
+		log::error!("Failed to remove upload {}: {}", upload.id, e);
+	}
+
+	let meta = UploadMeta {
+		bucket: state.config.name.clone(),
+		filename: metadata.get("filename").cloned(),
+		uploader,
+	};
+	let saved = match inspected {
+		Ok(buf) => buf.save(meta, state.config.verify_duplicates).await,
+		Err(e) => return Err(e.into_response()),
+	};
+
+	return match saved {
+		Ok((object, _)) => {
+			log::debug!("Resumable upload {} finished as {}", upload.id, object.hash);
+			Ok(object.hash)
+		}
+		Err(e) => Err(save_error(e)),
+	};
+}
+
+async fn terminate(state: State<ManualBucket>, Path(id): Path<String>, headers: HeaderMap) -> Response {
+	if let Some(response) = version_mismatch(&headers) {
+		return response;
+	}
+	let _guard = match state.ctx.upload_locks.acquire(&id) {
+		Some(guard) => guard,
+		None => return tus_response(StatusCode::CONFLICT),
+	};
+	if let Err(response) = load(&state, &id).await {
+		return response;
+	}
+	return match state.remove_upload(&id) {
+		Ok(_) => tus_response(StatusCode::NO_CONTENT),
+		Err(e) => {
+			log::error!("Failed to remove upload {}: {}", id, e);
+			tus_response(StatusCode::INTERNAL_SERVER_ERROR)
+		}
+	};
+}
+
+/// 定期删除过期的上传，所有存储桶共用一个任务。
+pub async fn expire_uploads(ctx: OSSContext) {
+	let mut interval = tokio::time::interval(Duration::from_secs(3600));
+	loop {
+		interval.tick().await;
+		match remove_expired(&ctx) {
+			Ok(0) => {}
+			Ok(count) => log::info!("Removed {} expired uploads", count),
+			Err(e) => log::error!("Failed to remove expired uploads: {}", e),
+		}
+	}
+}
+
+fn remove_expired(ctx: &OSSContext) -> DbResult<usize> {
+	let mut conn = ctx.db.get()?;
+	let expired = uploads::table.filter(uploads::expires_at.le(unix_time(SystemTime::now())));
+
+	// 正在追加或删除的上传跳过，留到下一轮，锁一直持有到记录删除后。
+	let ids: Vec<String> = expired.select(uploads::id).load(&mut conn)?;
+	let guards: Vec<LockGuard> = ids.iter().filter_map(|id| ctx.upload_locks.acquire(id)).collect();
+
+	for guard in &guards {
+		match std::fs::remove_file(part_path(ctx, &guard.id)) {
+			Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+			_ => {}
+		}
+	}
+	let locked = guards.iter().map(|guard| &guard.id);
+	diesel::delete(uploads::table.filter(uploads::id.eq_any(locked))).execute(&mut conn)?;
+	return Ok(guards.len());
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::body::Body;
+	use axum::http::{Request, StatusCode};
+	use axum::http::header::{CONTENT_TYPE, LOCATION};
+	use tower::ServiceExt;
+
+	use crate::context::test_context;
+	use crate::manual::{BucketConfig, manual_bucket, test_bucket};
+	use crate::tus::{OBJECT_HASH, OFFSET_OCTET_STREAM, parse_metadata, TUS_VERSION};
+
+	#[test]
+	fn metadata() {
+		let map = parse_metadata("filename d29ybGQucG5n, is_confidential,filetype aW1hZ2UvcG5n").unwrap();
+		assert_eq!(map["filename"], "world.png");
+		assert_eq!(map["filetype"], "image/png");
+		assert_eq!(map["is_confidential"], "");
+	}
+
+	#[test]
+	fn invalid_metadata() {
+		assert!(parse_metadata("filename !!!").is_none());
+	}
+
+	#[tokio::test]
+	async fn create_too_large() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut ctx = test_context(dir.path());
+		ctx.allow_anonymous = true;
+		let config = BucketConfig { max_size: Some(10), ..BucketConfig::default_image(dir.path()) };
+		let app = manual_bucket::<()>(test_bucket(ctx, config));
+
+		let create = |length: u64| Request::post("/tus")
+			.header("tus-resumable", TUS_VERSION)
+			.header("upload-length", length)
+			.body(Body::empty())
+			.unwrap();
+		let response = app.clone().oneshot(create(11)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+		let response = app.oneshot(create(10)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::CREATED);
+	}
+
+	#[tokio::test]
+	async fn upload_in_chunks() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut ctx = test_context(dir.path());
+		ctx.allow_anonymous = true;
+		let buf_dir = ctx.buf_dir.clone();
+		let app = manual_bucket::<()>(test_bucket(ctx, BucketConfig::default_image(dir.path())));
+		let call = |request: Request<Body>| app.clone().oneshot(request);
+
+		let data = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR-tus-upload";
+		let request = Request::post("/tus")
+			.header("tus-resumable", TUS_VERSION)
+			.header("upload-length", data.len())
+			.header("upload-metadata", "filename YS5wbmc=,filetype aW1hZ2UvcG5n")
+			.body(Body::empty())
+			.unwrap();
+		let response = call(request).await.unwrap();
+		assert_eq!(response.status(), StatusCode::CREATED);
+		let location = response.headers()[LOCATION].to_str().unwrap().to_owned();
+
+		let head = || Request::head(&location).header("tus-resumable", TUS_VERSION).body(Body::empty()).unwrap();
+		let patch = |offset: usize, chunk: &'static [u8]| Request::patch(&location)
+			.header("tus-resumable", TUS_VERSION)
+			.header(CONTENT_TYPE, OFFSET_OCTET_STREAM)
+			.header("upload-offset", offset)
+			.body(Body::from(chunk))
+			.unwrap();
+
+		let response = call(head()).await.unwrap();
+		assert_eq!(response.headers()["upload-offset"], "0");
+		assert_eq!(response.headers()["upload-length"], data.len().to_string().as_str());
+
+		let response = call(patch(0, &data[..10])).await.unwrap();
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		assert_eq!(response.headers()["upload-offset"], "10");
+		assert!(response.headers().get(OBJECT_HASH).is_none());
+
+		// 偏移量与已接收的长度不同，比如重发了已确认的块。
+		let response = call(patch(0, &data[..10])).await.unwrap();
+		assert_eq!(response.status(), StatusCode::CONFLICT);
+		let response = call(head()).await.unwrap();
+		assert_eq!(response.headers()["upload-offset"], "10");
+
+		let response = call(patch(10, &data[10..])).await.unwrap();
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		assert_eq!(response.headers()["upload-offset"], data.len().to_string().as_str());
+		let hash = response.headers()[OBJECT_HASH].to_str().unwrap().to_owned();
+
+		// 完成后上传被删除，对象可以下载。
+		assert_eq!(call(head()).await.unwrap().status(), StatusCode::NOT_FOUND);
+		assert_eq!(std::fs::read_dir(buf_dir.join("tus")).map(|d| d.count()).unwrap_or(0), 0);
+
+		let response = call(Request::get(format!("/{}", hash)).body(Body::empty()).unwrap()).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(response.headers()[CONTENT_TYPE], "image/png");
+		let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+		assert_eq!(&body[..], data);
+	}
+}
@@ -0,0 +1,240 @@
+use std::io::{self, BufRead, Write};
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use clap::{Subcommand, ValueEnum};
+use diesel::prelude::*;
+
+use crate::db::{DbPool, DbResult, unix_time};
+use crate::schema::{sessions, users};
+use crate::token::Scope;
+
+/*
+ * 用户账户，密码用 Argon2id 的默认参数 Hash，结果是包含参数和盐的 PHC 字符串，
+ * 以后调整参数也不影响已有的密码。用户只能通过命令行管理，见 UserCommand。
+ */
+
+/// 用户的角色，决定能使用哪些权限（见 token.rs 的 Scope）。
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Role {
+	/// 所有权限，包括管理令牌和会话。
+	Admin,
+	/// 上传、删除和读取对象。
+	Editor,
+	/// 只能读取私有存储桶和列出对象。
+	Viewer,
+}
+
+impl Role {
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Role::Admin => "admin",
+			Role::Editor => "editor",
+			Role::Viewer => "viewer",
+		}
+	}
+
+	pub fn parse(name: &str) -> Option<Self> {
+		match name {
+			"admin" => Some(Role::Admin),
+			"editor" => Some(Role::Editor),
+			"viewer" => Some(Role::Viewer),
+			_ => None,
+		}
+	}
+
+	pub fn allows(self, scope: Scope) -> bool {
+		match self {
+			Role::Admin => true,
+			Role::Editor => scope != Scope::Admin,
+			Role::Viewer => scope == Scope::Read,
+		}
+	}
+}
+
+/// 通过认证的用户。
+#[derive(Clone, Debug)]
+pub struct User {
+	pub name: String,
+	pub role: Role,
+}
+
+pub fn hash_password(password: &str) -> String {
+	let salt = SaltString::generate(&mut OsRng);
+	return Argon2::default().hash_password(password.as_bytes(), &salt).unwrap().to_string();
+}
+
+fn verify_hash(password: &str, hash: &str) -> bool {
+	return match PasswordHash::new(hash) {
+		Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+		Err(e) => {
+			log::error!("Invalid password hash: {}", e);
+			false
+		}
+	};
+}
+
+/// 检查用户名和密码，Argon2 很慢，应当在阻塞线程中调用。
+///
+/// 用户不存在时也计算一次 Hash，以免通过响应时间判断用户名是否存在。
+pub fn authenticate(db: &DbPool, name: &str, password: &str) -> DbResult<Option<User>> {
+	static DUMMY: OnceLock<String> = OnceLock::new();
+
+	let row: Option<(String, String)> = users::table
+		.find(name)
+		.select((users::password_hash, users::role))
+		.first(&mut db.get()?)
+		.optional()?;
+
+	let Some((hash, role)) = row else {
+		verify_hash(password, DUMMY.get_or_init(|| hash_password("")));
+		return Ok(None);
+	};
+	if !verify_hash(password, &hash) {
+		return Ok(None);
+	}
+	let role = Role::parse(&role).ok_or_else(|| format!("Invalid role of user {}: {}", name, role))?;
+	return Ok(Some(User { name: name.to_owned(), role }));
+}
+
+pub fn count_users(db: &DbPool) -> DbResult<i64> {
+	return Ok(users::table.count().get_result(&mut db.get()?)?);
+}
+
+/// 兼容旧版的单一密码：没有任何用户时，用它创建名为 admin 的管理员。
+pub fn bootstrap_admin(db: &DbPool, password: &str) -> DbResult<()> {
+	if count_users(db)? > 0 {
+		log::warn!("The password option is ignored because user accounts exist");
+		return Ok(());
+	}
+	add_user(db, "admin", password, Role::Admin)?;
+	log::warn!("Created user admin from the deprecated password option, please remove it from the config");
+	return Ok(());
+}
+
+fn add_user(db: &DbPool, name: &str, password: &str, role: Role) -> DbResult<()> {
+	diesel::insert_into(users::table)
+		.values((
+			users::name.eq(name),
+			users::password_hash.eq(hash_password(password)),
+			users::role.eq(role.name()),
+			users::created_at.eq(unix_time(SystemTime::now())),
+		))
+		.execute(&mut db.get()?)?;
+	return Ok(());
+}
+
+/// 管理用户的命令行子命令，密码从标准输入读取一行，以免留在命令历史里。
+#[derive(Subcommand, Debug)]
+pub enum UserCommand {
+	/// Add a user.
+	Add {
+		name: String,
+		#[arg(long, value_enum, default_value = "viewer")]
+		role: Role,
+	},
+	/// Reset the password of a user and sign out all its sessions.
+	Reset {
+		name: String,
+		/// Also change the role.
+		#[arg(long, value_enum)]
+		role: Option<Role>,
+	},
+	/// Remove a user and its sessions.
+	Remove {
+		name: String,
+	},
+	/// List all users.
+	List,
+}
+
+fn read_password(name: &str) -> io::Result<String> {
+	eprint!("Password for {}: ", name);
+	io::stderr().flush()?;
+
+	let mut line = String::new();
+	io::stdin().lock().read_line(&mut line)?;
+	let password = line.trim_end_matches(['\r', '\n']).to_owned();
+
+	if password.is_empty() {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, "Password must not be empty"));
+	}
+	return Ok(password);
+}
+
+pub fn run_command(db: &DbPool, command: UserCommand) -> DbResult<()> {
+	match command {
+		UserCommand::Add { name, role } => {
+			if users::table.find(&name).count().get_result::<i64>(&mut db.get()?)? > 0 {
+				return Err(format!("User {} already exists", name).into());
+			}
+			add_user(db, &name, &read_password(&name)?, role)?;
+			println!("Added user {} ({})", name, role.name());
+		}
+		UserCommand::Reset { name, role } => {
+			let password = read_password(&name)?;
+			let mut conn = db.get()?;
+			let target = users::table.find(&name);
+
+			let updated = match role {
+				Some(role) => diesel::update(target)
+					.set((users::password_hash.eq(hash_password(&password)), users::role.eq(role.name())))
+					.execute(&mut conn)?,
+				None => diesel::update(target)
+					.set(users::password_hash.eq(hash_password(&password)))
+					.execute(&mut conn)?,
+			};
+			if updated == 0 {
+				return Err(format!("User {} does not exist", name).into());
+			}
+			diesel::delete(sessions::table.filter(sessions::username.eq(&name))).execute(&mut conn)?;
+			println!("Reset password of user {}", name);
+		}
+		UserCommand::Remove { name } => {
+			// 会话通过外键级联删除。
+			let deleted = diesel::delete(users::table.find(&name)).execute(&mut db.get()?)?;
+			if deleted == 0 {
+				return Err(format!("User {} does not exist", name).into());
+			}
+			println!("Removed user {}", name);
+		}
+		UserCommand::List => {
+			let list: Vec<(String, String)> = users::table
+				.select((users::name, users::role))
+				.order(users::name)
+				.load(&mut db.get()?)?;
+			for (name, role) in list {
+				println!("{}\t{}", name, role);
+			}
+		}
+	}
+	return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::token::Scope;
+	use crate::user::{hash_password, Role, verify_hash};
+
+	#[test]
+	fn password_hash() {
+		let hash = hash_password("secret");
+		assert!(hash.starts_with("$argon2id$"));
+		assert!(verify_hash("secret", &hash));
+		assert!(!verify_hash("Secret", &hash));
+		assert_ne!(hash, hash_password("secret"));
+	}
+
+	#[test]
+	fn roles() {
+		assert!(Role::Admin.allows(Scope::Admin));
+		assert!(Role::Editor.allows(Scope::Delete));
+		assert!(!Role::Editor.allows(Scope::Admin));
+		assert!(Role::Viewer.allows(Scope::Read));
+		assert!(!Role::Viewer.allows(Scope::Upload));
+	}
+}